@@ -0,0 +1,63 @@
+use std::{io::Read, path::Path, process::Command, time::Duration};
+
+use sha2::{Digest, Sha256};
+
+use crate::repo::PackageRepoError;
+
+/// Records which location and checksum an unpacked binary target came from,
+/// so an unchanged pin is not downloaded again.
+pub const MARKER_FILE: &str = ".spm-git-swap-binary";
+
+/// Downloads `url` to `path`, going through `http_proxy` unless it is
+/// `auto`, which leaves curl to pick the proxy from the environment.
+// There is no HTTP client among the dependencies, so this shells out to curl
+// the same way bundles shell out to git.
+pub fn download(
+    url: &str,
+    path: &Path,
+    http_proxy: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<(), PackageRepoError> {
+    let mut command = Command::new("curl");
+    command
+        .args(["--fail", "--silent", "--show-error", "--location", "--output"])
+        .arg(path);
+    if let Some(proxy) = http_proxy.filter(|proxy| *proxy != "auto") {
+        command.args(["--proxy", proxy]);
+    }
+    if let Some(timeout) = timeout {
+        command
+            .arg("--connect-timeout")
+            .arg(timeout.as_secs().max(1).to_string());
+    }
+
+    let output = command.arg(url).output()?;
+    if !output.status.success() {
+        return Err(PackageRepoError::Download(format!(
+            "{}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// The lowercase hex SHA-256 of the file at `path`, as SwiftPM computes
+/// binary target checksums.
+pub fn checksum(path: &Path) -> Result<String, PackageRepoError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
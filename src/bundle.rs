@@ -0,0 +1,87 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::repo::PackageRepoError;
+
+/// Name of the manifest inside a cache bundle archive.
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Directory inside a cache bundle archive holding one git bundle per
+/// checkout.
+pub const BUNDLES_DIR: &str = "bundles";
+
+/// A scratch directory for assembling or unpacking an archive. Removed when
+/// dropped.
+pub struct Staging(PathBuf);
+
+impl Staging {
+    pub fn new() -> Result<Self, PackageRepoError> {
+        let path =
+            std::env::temp_dir().join(format!("spm-git-swap-bundle-{}", std::process::id()));
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(path.join(BUNDLES_DIR))?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn bundle_path(&self, identity: &str) -> PathBuf {
+        self.0.join(BUNDLES_DIR).join(format!("{}.bundle", identity))
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes every ref of the repository at `repo` to a git bundle.
+pub fn create(repo: &Path, bundle: &Path) -> Result<(), PackageRepoError> {
+    git([
+        OsStr::new("-C"),
+        repo.as_os_str(),
+        OsStr::new("bundle"),
+        OsStr::new("create"),
+        bundle.as_os_str(),
+        OsStr::new("--all"),
+    ])
+}
+
+/// Clones the repository stored in `bundle` to `path`, as a bare repository
+/// with `bare`.
+pub fn clone(bundle: &Path, path: &Path, bare: bool) -> Result<(), PackageRepoError> {
+    let mut args = vec![OsStr::new("clone"), OsStr::new("--quiet")];
+    if bare {
+        args.push(OsStr::new("--bare"));
+    }
+    args.extend([bundle.as_os_str(), path.as_os_str()]);
+    git(args)
+}
+
+/// Whether `path` is named like a tar archive, such as `cache.tar` or
+/// `cache.tar.gz`, which a zip archive shouldn't be written to.
+pub fn is_tar_name(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    name.ends_with(".tar") || name.contains(".tar.") || name.ends_with(".tgz")
+}
+
+// libgit2 can't read or write bundles, so these shell out to git.
+fn git<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> Result<(), PackageRepoError> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        return Err(PackageRepoError::Bundle(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::url;
+
+/// The key a repository is stored under in a content-addressed store: the
+/// lowercase hex SHA-256 of its normalized URL, so `https://host/repo.git`
+/// and `https://host/repo` share a key.
+pub fn key(url: &str) -> String {
+    Sha256::digest(url::normalize_location(url).as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Where the bare clone of `url` lives in the store at `cas_dir`:
+/// `<cas_dir>/<first two hex digits of the key>/<key>.git`.
+pub fn path(cas_dir: &Path, url: &str) -> PathBuf {
+    let key = key(url);
+    cas_dir.join(&key[..2]).join(format!("{}.git", key))
+}
@@ -0,0 +1,197 @@
+use std::str::FromStr;
+
+use git2::{build::CheckoutBuilder, BranchType, Oid, Repository};
+use log::{info, warn};
+
+use crate::{repo::PackageRepoError, resolved::v2};
+
+/// Which ref a checkout's HEAD is moved to after cloning or fetching.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckoutBy {
+    /// Detach HEAD at the pinned revision.
+    #[default]
+    Revision,
+    /// Detach HEAD at the tag named after the pinned version, so `git
+    /// describe` reports the version.
+    Version,
+    /// Put HEAD on a local branch named after the pinned branch.
+    Branch,
+}
+
+impl FromStr for CheckoutBy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "revision" => Ok(CheckoutBy::Revision),
+            "version" => Ok(CheckoutBy::Version),
+            "branch" => Ok(CheckoutBy::Branch),
+            _ => Err(format!(
+                "unknown checkout ref {}, expected revision, version or branch",
+                value
+            )),
+        }
+    }
+}
+
+/// Moves HEAD of `repo` to the pinned revision, through the ref `by` prefers
+/// when the pin records one that points at the revision. Falls back to the
+/// bare revision otherwise.
+pub fn checkout(
+    repo: &Repository,
+    pin: &v2::Pin,
+    by: CheckoutBy,
+    remote_name: &str,
+) -> Result<(), PackageRepoError> {
+    let commit = repo
+        .revparse_single(&pin.state.revision)?
+        .peel_to_commit()?;
+    let revision = commit.id();
+
+    // Bare clones, as kept in a content-addressed store, have no work tree.
+    if !repo.is_bare() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe();
+        repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+    }
+    // Detach first so the branch below can be moved even if it is checked out.
+    repo.set_head_detached(revision)?;
+
+    match by {
+        CheckoutBy::Revision => {}
+        CheckoutBy::Version => match &pin.state.version {
+            Some(version) => match find_tag(repo, version, revision) {
+                Some(tag) => {
+                    info!("Checked out {} at tag {}", pin.identity, tag);
+                    return Ok(());
+                }
+                None => warn!(
+                    "No tag for version {} of {} points at {}, checking out the revision",
+                    version, pin.identity, pin.state.revision
+                ),
+            },
+            None => warn!("{} has no version, checking out the revision", pin.identity),
+        },
+        CheckoutBy::Branch => match &pin.state.branch {
+            Some(branch) => {
+                let mut local = repo.branch(branch, &commit, true)?;
+                let upstream = format!("{}/{}", remote_name, branch);
+                if repo.find_branch(&upstream, BranchType::Remote).is_ok() {
+                    local.set_upstream(Some(&upstream))?;
+                }
+                repo.set_head(&format!("refs/heads/{}", branch))?;
+                info!("Checked out {} on branch {}", pin.identity, branch);
+                return Ok(());
+            }
+            None => warn!("{} has no branch, checking out the revision", pin.identity),
+        },
+    }
+
+    info!("Checked out {} at {}", pin.identity, pin.state.revision);
+    Ok(())
+}
+
+/// Finds the tag for `version`, with or without a `v` prefix, that peels to
+/// `revision`.
+fn find_tag(repo: &Repository, version: &str, revision: Oid) -> Option<String> {
+    [version.to_string(), format!("v{}", version)]
+        .into_iter()
+        .find(|tag| {
+            repo.revparse_single(&format!("refs/tags/{}", tag))
+                .and_then(|object| object.peel_to_commit())
+                .is_ok_and(|commit| commit.id() == revision)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{DescribeOptions, Signature};
+
+    use super::*;
+    use crate::test_support::{self, TempDir};
+
+    /// A repository with two commits, the first tagged `tag`, and a pin of
+    /// its first commit at `version`.
+    fn tagged(dir: &TempDir, tag: &str, version: &str) -> (Repository, v2::Pin) {
+        let revisions = test_support::init_repo(&dir.join("repo"), &["one", "two"]);
+        let repo = Repository::open(dir.join("repo")).unwrap();
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        repo.tag(
+            tag,
+            &repo.find_object(revisions[0], None).unwrap(),
+            &signature,
+            tag,
+            false,
+        )
+        .unwrap();
+        let pin = v2::Pin {
+            identity: String::from("repo"),
+            kind: v2::Kind::RemoteSourceControl,
+            location: dir.join("repo").display().to_string(),
+            state: v2::State {
+                branch: Some(String::from("release")),
+                revision: revisions[0].to_string(),
+                version: Some(version.to_string()),
+                checksum: None,
+            },
+        };
+        (repo, pin)
+    }
+
+    fn describe(repo: &Repository) -> String {
+        repo.describe(DescribeOptions::new().describe_tags())
+            .and_then(|describe| describe.format(None))
+            .unwrap()
+    }
+
+    fn head(repo: &Repository) -> String {
+        repo.head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id()
+            .to_string()
+    }
+
+    #[test]
+    fn by_revision_detaches_at_the_revision() {
+        let dir = TempDir::new("checkout-revision");
+        let (repo, pin) = tagged(&dir, "1.0.0", "1.0.0");
+
+        checkout(&repo, &pin, CheckoutBy::Revision, "origin").unwrap();
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(head(&repo), pin.state.revision);
+    }
+
+    #[test]
+    fn by_version_lands_on_the_tag() {
+        for tag in ["1.0.0", "v1.0.0"] {
+            let dir = TempDir::new("checkout-version");
+            let (repo, pin) = tagged(&dir, tag, "1.0.0");
+
+            checkout(&repo, &pin, CheckoutBy::Version, "origin").unwrap();
+            assert_eq!(head(&repo), pin.state.revision);
+            assert_eq!(describe(&repo), tag);
+        }
+    }
+
+    #[test]
+    fn by_version_falls_back_to_the_revision_without_a_matching_tag() {
+        let dir = TempDir::new("checkout-version-missing");
+        let (repo, pin) = tagged(&dir, "0.9.0", "1.0.0");
+
+        checkout(&repo, &pin, CheckoutBy::Version, "origin").unwrap();
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(head(&repo), pin.state.revision);
+    }
+
+    #[test]
+    fn by_branch_puts_head_on_the_pinned_branch() {
+        let dir = TempDir::new("checkout-branch");
+        let (repo, pin) = tagged(&dir, "1.0.0", "1.0.0");
+
+        checkout(&repo, &pin, CheckoutBy::Branch, "origin").unwrap();
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/release"));
+        assert_eq!(head(&repo), pin.state.revision);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::{resolved::v2, url};
+
+/// How validation findings are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// Log them like any other message.
+    #[default]
+    Human,
+    /// GitHub Actions workflow commands, so they show up as annotations.
+    Github,
+    /// One JSON object per finding on stdout.
+    Json,
+}
+
+impl FromStr for DiagnosticsFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "human" => Ok(DiagnosticsFormat::Human),
+            "github" => Ok(DiagnosticsFormat::Github),
+            "json" => Ok(DiagnosticsFormat::Json),
+            _ => Err(format!(
+                "unknown diagnostics format {}, expected human, github or json",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a resolved file.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A stable identifier for the kind of problem.
+    pub code: &'static str,
+    pub message: String,
+    pub file: PathBuf,
+    /// The 1-based line mentioning the pin, when it could be found.
+    pub line: Option<usize>,
+}
+
+impl Diagnostic {
+    /// Creates a finding about `pin`, pointing at the line in `file` that
+    /// mentions the pin's location.
+    pub fn for_pin(
+        severity: Severity,
+        code: &'static str,
+        message: String,
+        file: &Path,
+        pin: &v2::Pin,
+    ) -> Self {
+        Self {
+            severity,
+            code,
+            message,
+            file: file.to_path_buf(),
+            line: find_line(file, &pin.location),
+        }
+    }
+
+    pub fn emit(&self, format: DiagnosticsFormat) {
+        match format {
+            DiagnosticsFormat::Human => {
+                let location = match self.line {
+                    Some(line) => format!("{}:{}", self.file.display(), line),
+                    None => self.file.display().to_string(),
+                };
+                match self.severity {
+                    Severity::Warning => warn!("{} [{}] {}", location, self.code, self.message),
+                    Severity::Error => error!("{} [{}] {}", location, self.code, self.message),
+                }
+            }
+            DiagnosticsFormat::Github => {
+                let command = match self.severity {
+                    Severity::Warning => "warning",
+                    Severity::Error => "error",
+                };
+                let line = self
+                    .line
+                    .map(|line| format!(",line={}", line))
+                    .unwrap_or_default();
+                println!(
+                    "::{} file={}{},title={}::{}",
+                    command,
+                    escape_property(&self.file.display().to_string()),
+                    line,
+                    self.code,
+                    escape_data(&self.message)
+                );
+            }
+            DiagnosticsFormat::Json => {
+                if let Ok(line) = serde_json::to_string(self) {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+}
+
+/// Checks the pins of each resolved file for malformed revisions and for
+/// identities that are pinned differently in different files.
+pub fn validate(files: &[(PathBuf, v2::Resolved)]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut first_seen: BTreeMap<&str, (&Path, &v2::Pin)> = BTreeMap::new();
+
+    for (file, resolved) in files {
+        for pin in &resolved.pins {
+            let revision = &pin.state.revision;
+            if pin.kind != v2::Kind::BinaryTarget
+                && pin.state.followed_branch().is_none()
+                && (revision.len() != 40 || !revision.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                diagnostics.push(Diagnostic::for_pin(
+                    Severity::Warning,
+                    "invalid-revision",
+                    format!(
+                        "{} is pinned to {:?}, which is not a commit id",
+                        pin.identity, revision
+                    ),
+                    file,
+                    pin,
+                ));
+            }
+
+            match first_seen.get(pin.identity.as_str()) {
+                Some((other_file, other))
+                    if url::normalize_location(&other.location)
+                        != url::normalize_location(&pin.location)
+                        || other.state != pin.state =>
+                {
+                    diagnostics.push(Diagnostic::for_pin(
+                        Severity::Warning,
+                        "duplicate-identity",
+                        format!(
+                            "{} is pinned differently in {}",
+                            pin.identity,
+                            other_file.display()
+                        ),
+                        file,
+                        pin,
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    first_seen.insert(&pin.identity, (file, pin));
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn find_line(file: &Path, needle: &str) -> Option<usize> {
+    let contents = std::fs::read_to_string(file).ok()?;
+    let quoted = format!("\"{}\"", needle);
+    contents
+        .lines()
+        .position(|line| line.contains(&quoted))
+        .map(|index| index + 1)
+}
+
+// Escaping rules for GitHub Actions workflow commands.
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+
+use crate::resolved::v2;
+
+/// Prefix of the variable each pin's revision is exported as.
+pub const PREFIX: &str = "SPM_PIN_";
+
+/// The environment variable name for `identity`: `SPM_PIN_` followed by the
+/// identity in upper case, with every character other than an ASCII letter
+/// or digit replaced by `_`.
+pub fn name(identity: &str) -> String {
+    let sanitized: String = identity
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}{}", PREFIX, sanitized)
+}
+
+/// One `export NAME=revision` line per pin. When identities sanitize to the
+/// same name, the first pin keeps it and the others are left out with a
+/// warning.
+pub fn exports(pins: &[v2::Pin]) -> Vec<String> {
+    let mut names: BTreeMap<String, &v2::Pin> = BTreeMap::new();
+    for pin in pins {
+        let name = name(&pin.identity);
+        match names.get(&name) {
+            Some(existing) => warn!(
+                "{} and {} both map to {}, leaving {} out",
+                existing.identity, pin.identity, name, pin.identity
+            ),
+            None => {
+                names.insert(name, pin);
+            }
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|(name, pin)| format!("export {}={}", name, pin.state.revision))
+        .collect()
+}
@@ -0,0 +1,13 @@
+use std::path::Path;
+
+use crate::repo::PackageRepoError;
+
+/// Writes `contents` next to `path` first and renames them into place so a
+/// reader never sees a partial file.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), PackageRepoError> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
@@ -0,0 +1,659 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use git2::{Config, ConfigLevel};
+use log::{error, info};
+
+use crate::repo::PackageRepoError;
+
+pub const INSTEAD_OF: &str = "insteadOf";
+pub const PUSH_INSTEAD_OF: &str = "pushInsteadOf";
+/// Marks the `url.<path>` values this tool wrote, one value per url, so
+/// values added by the user under the same section are left alone. Git
+/// ignores variables it doesn't know.
+pub const MANAGED: &str = "spmGitSwapManaged";
+
+/// Set once any value has been marked with [`MANAGED`].
+pub const MARKS_VALUES: &str = "spmGitSwap.markedValues";
+
+/// Which `url.<path>.*` keys are written for each checkout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyStyle {
+    /// Redirect fetches only.
+    #[default]
+    InsteadOf,
+    /// Redirect pushes only.
+    PushInsteadOf,
+    /// Redirect both fetches and pushes.
+    Both,
+}
+
+impl KeyStyle {
+    pub fn variables(self) -> &'static [&'static str] {
+        match self {
+            KeyStyle::InsteadOf => &[INSTEAD_OF],
+            KeyStyle::PushInsteadOf => &[PUSH_INSTEAD_OF],
+            KeyStyle::Both => &[INSTEAD_OF, PUSH_INSTEAD_OF],
+        }
+    }
+}
+
+impl FromStr for KeyStyle {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "insteadOf" => Ok(KeyStyle::InsteadOf),
+            "pushInsteadOf" => Ok(KeyStyle::PushInsteadOf),
+            "both" => Ok(KeyStyle::Both),
+            _ => Err(format!(
+                "unknown key style {}, expected insteadOf, pushInsteadOf or both",
+                value
+            )),
+        }
+    }
+}
+
+/// An `insteadOf` or `pushInsteadOf` entry that redirects `url` to the
+/// checkout at `path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyEntry {
+    pub key: String,
+    pub variable: &'static str,
+    pub path: PathBuf,
+    pub url: String,
+}
+
+/// Opens the global and system git config like `Config::open_default`, but
+/// honouring `GIT_CONFIG_GLOBAL`, `GIT_CONFIG_SYSTEM` and
+/// `GIT_CONFIG_NOSYSTEM`, which libgit2 ignores. Changes are written to the
+/// global file, so setting `GIT_CONFIG_GLOBAL` redirects them.
+pub fn open_default() -> Result<Config, git2::Error> {
+    let global = env_path("GIT_CONFIG_GLOBAL");
+    let system = env_path("GIT_CONFIG_SYSTEM");
+    if global.is_none() && system.is_none() {
+        return Config::open_default();
+    }
+
+    let mut config = Config::new()?;
+    if std::env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+        if let Some(system) = system
+            .or_else(|| Config::find_system().ok())
+            .filter(|path| path.exists())
+        {
+            config.add_file(&system, ConfigLevel::System, false)?;
+        }
+    }
+    match global {
+        Some(global) => config.add_file(&global, ConfigLevel::Global, false)?,
+        None => {
+            if let Ok(xdg) = Config::find_xdg() {
+                config.add_file(&xdg, ConfigLevel::XDG, false)?;
+            }
+            let global = Config::find_global().or_else(|err| {
+                std::env::var_os("HOME")
+                    .map(|home| Path::new(&home).join(".gitconfig"))
+                    .ok_or(err)
+            })?;
+            config.add_file(&global, ConfigLevel::Global, false)?;
+        }
+    }
+    Ok(config)
+}
+
+/// Opens the config of the git repository at `local` when given, writing to
+/// its `.git/config`, and the global config otherwise.
+pub fn open(local: Option<&Path>) -> Result<Config, git2::Error> {
+    match local {
+        Some(local) => git2::Repository::open(local)?
+            .config()?
+            .open_level(ConfigLevel::Local),
+        None => open_default(),
+    }
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// A throwaway global git config for a single run, so the user's own config
+/// is never read or changed. Removed when dropped unless kept.
+pub struct IsolatedConfig {
+    dir: PathBuf,
+    keep: bool,
+}
+
+impl IsolatedConfig {
+    /// Creates an empty global config in a temporary directory and points
+    /// both `GIT_CONFIG_GLOBAL` and libgit2's own lookup at it. Must be
+    /// called before any other thread uses libgit2.
+    pub fn new(keep: bool) -> Result<Self, PackageRepoError> {
+        let dir =
+            std::env::temp_dir().join(format!("spm-git-swap-isolated-{}", std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join(".gitconfig");
+        std::fs::write(&file, "")?;
+
+        std::env::set_var("GIT_CONFIG_GLOBAL", &file);
+        // Repository configs look up the global levels through libgit2's
+        // search path rather than `open_default` above.
+        // SAFETY: nothing else is using libgit2 yet.
+        unsafe {
+            git2::opts::set_search_path(ConfigLevel::Global, dir.as_path())?;
+            git2::opts::set_search_path(ConfigLevel::XDG, dir.as_path())?;
+        }
+
+        info!("Using an isolated git config at {}", file.display());
+        Ok(Self { dir, keep })
+    }
+}
+
+impl Drop for IsolatedConfig {
+    fn drop(&mut self) {
+        if self.keep {
+            info!(
+                "Keeping the isolated git config at {}",
+                self.dir.join(".gitconfig").display()
+            );
+        } else {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Builds the `url.<proxy_path>.<variable>` config key.
+pub fn proxy_key_with(proxy_path: &str, variable: &str) -> String {
+    format!("url.{}.{}", proxy_path, variable)
+}
+
+/// Builds the key marking the values this tool wrote for `proxy_path`.
+pub fn managed_key(proxy_path: &str) -> String {
+    proxy_key_with(proxy_path, MANAGED)
+}
+
+/// Whether values in `config` have ever been marked. Configs written by
+/// versions before the marker existed don't have this, and their entries are
+/// all ours. It is kept after teardown, so a wiped config isn't mistaken for
+/// one of those.
+pub fn marks_values(config: &Config) -> bool {
+    config.get_bool(MARKS_VALUES).unwrap_or(false)
+}
+
+/// Lists every `url.<path>.insteadOf` and `url.<path>.pushInsteadOf` entry in
+/// `config`.
+pub fn proxy_entries(config: &Config) -> Result<Vec<ProxyEntry>, git2::Error> {
+    let mut proxies = Vec::new();
+    let mut entries = config.entries(Some(r"^url\..*\.(push)?insteadof$"))?;
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let (Some(name), Some(value)) = (entry.name(), entry.value()) else {
+            continue;
+        };
+        let Some((path, variable)) = split_proxy_key(name) else {
+            continue;
+        };
+        proxies.push(ProxyEntry {
+            key: proxy_key_with(path, variable),
+            variable,
+            path: PathBuf::from(path),
+            url: value.to_string(),
+        });
+    }
+    Ok(proxies)
+}
+
+/// Splits a `url.<path>.<variable>` key into the path and the canonical
+/// spelling of the variable.
+fn split_proxy_key(key: &str) -> Option<(&str, &'static str)> {
+    let (section, variable) = key.strip_prefix("url.")?.rsplit_once('.')?;
+    if variable.eq_ignore_ascii_case(PUSH_INSTEAD_OF) {
+        Some((section, PUSH_INSTEAD_OF))
+    } else if variable.eq_ignore_ascii_case(INSTEAD_OF) {
+        Some((section, INSTEAD_OF))
+    } else {
+        None
+    }
+}
+
+/// Lists the `insteadOf` entries whose target lives inside `dir`.
+pub fn proxy_entries_under(config: &Config, dir: &Path) -> Result<Vec<ProxyEntry>, git2::Error> {
+    Ok(proxy_entries(config)?
+        .into_iter()
+        .filter(|entry| entry.path.starts_with(dir))
+        .collect())
+}
+
+/// Renders the staged proxy entries as a `.gitconfig` fragment.
+pub fn render_fragment(ops: &[ConfigOp]) -> String {
+    let mut fragment = String::new();
+    let mut section = None;
+    for op in ops {
+        let (ConfigOp::Set { key, value } | ConfigOp::Add { key, value }) = op else {
+            continue;
+        };
+        let Some((path, variable)) = split_proxy_key(key) else {
+            continue;
+        };
+        if section != Some(path) {
+            fragment.push_str(&format!("[url \"{}\"]\n", escape(path)));
+            section = Some(path);
+        }
+        fragment.push_str(&format!("\t{} = {}\n", variable, quote_value(value)));
+    }
+    fragment
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Quotes a value that git would otherwise read differently: one containing
+/// a comment character, a quote or a backslash, or surrounded by whitespace.
+fn quote_value(value: &str) -> String {
+    if value.contains(['#', ';', '"', '\\']) || value.trim() != value {
+        format!("\"{}\"", escape(value))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A single pending change to the git config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigOp {
+    /// Replaces every value of the key.
+    Set { key: String, value: String },
+    /// Adds a value next to the ones the key already has.
+    Add { key: String, value: String },
+    Remove { key: String },
+    /// Removes a single value of the key, keeping the others.
+    RemoveValue { key: String, value: String },
+}
+
+impl ConfigOp {
+    fn key(&self) -> &str {
+        match self {
+            ConfigOp::Set { key, .. }
+            | ConfigOp::Add { key, .. }
+            | ConfigOp::Remove { key }
+            | ConfigOp::RemoveValue { key, .. } => key,
+        }
+    }
+}
+
+/// Stages git config writes so that a run either applies all of them or none.
+///
+/// When `eager` is set every operation is applied immediately, which matches
+/// the behaviour before transactions were introduced.
+pub struct ConfigTransaction {
+    eager: bool,
+    ops: Vec<ConfigOp>,
+    /// Restores the values taken out by [`Self::lift`] on rollback.
+    lifted: Vec<ConfigOp>,
+    /// The repository whose own config is written instead of the global one.
+    local: Option<PathBuf>,
+}
+
+impl ConfigTransaction {
+    pub fn new(eager: bool) -> Self {
+        Self {
+            eager,
+            ops: Vec::new(),
+            lifted: Vec::new(),
+            local: None,
+        }
+    }
+
+    /// Writes to the config of the git repository at `local` instead of the
+    /// global config.
+    pub fn with_local(mut self, local: Option<PathBuf>) -> Self {
+        self.local = local;
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), PackageRepoError> {
+        self.push(ConfigOp::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: &str) -> Result<(), PackageRepoError> {
+        self.push(ConfigOp::Add {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), PackageRepoError> {
+        self.push(ConfigOp::Remove {
+            key: key.to_string(),
+        })
+    }
+
+    pub fn remove_value(&mut self, key: &str, value: &str) -> Result<(), PackageRepoError> {
+        self.push(ConfigOp::RemoveValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    /// Removes a value right away instead of at commit, and puts it back on
+    /// rollback. Only meant for values a staged operation removes anyway.
+    pub fn lift(&mut self, key: &str, value: &str) -> Result<(), PackageRepoError> {
+        let mut config = open(self.local.as_deref())?;
+        apply(
+            &mut config,
+            &ConfigOp::RemoveValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            },
+        )?;
+        if !self.eager {
+            self.lifted.push(ConfigOp::Add {
+                key: key.to_string(),
+                value: value.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies every staged operation in one pass. If any of them fails the
+    /// touched keys are restored to the values they had before the commit.
+    pub fn commit(&mut self) -> Result<(), PackageRepoError> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+
+        let ops = std::mem::take(&mut self.ops);
+        info!("Applying {} staged git config change(s)", ops.len());
+
+        let mut config = open(self.local.as_deref())?;
+        let snapshot = snapshot(&config, &ops);
+        // A failed commit is rolled back, which puts them back.
+        let lifted = std::mem::take(&mut self.lifted);
+
+        for op in &ops {
+            if let Err(err) = apply(&mut config, op) {
+                error!(
+                    "Failed to apply git config change to {}: {}. Restoring previous config",
+                    op.key(),
+                    err
+                );
+                restore(&mut config, &snapshot);
+                self.lifted = lifted;
+                return Err(PackageRepoError::GitConfig(format!(
+                    "failed to apply change to {}: {}",
+                    op.key(),
+                    err
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The operations that have been staged but not yet applied.
+    pub fn staged(&self) -> &[ConfigOp] {
+        &self.ops
+    }
+
+    /// Drops all staged operations without applying them, and puts back the
+    /// lifted values.
+    pub fn rollback(&mut self) {
+        if !self.ops.is_empty() {
+            info!("Discarding {} staged git config change(s)", self.ops.len());
+            self.ops.clear();
+        }
+        if self.lifted.is_empty() {
+            return;
+        }
+        let lifted = std::mem::take(&mut self.lifted);
+        let restored = open(self.local.as_deref())
+            .and_then(|mut config| lifted.iter().try_for_each(|op| apply(&mut config, op)));
+        if let Err(err) = restored {
+            error!("Failed to restore lifted git config values: {}", err);
+        }
+    }
+
+    fn push(&mut self, op: ConfigOp) -> Result<(), PackageRepoError> {
+        if self.eager {
+            let mut config = open(self.local.as_deref())?;
+            apply(&mut config, &op)?;
+        } else {
+            self.ops.push(op);
+        }
+        Ok(())
+    }
+}
+
+fn apply(config: &mut Config, op: &ConfigOp) -> Result<(), git2::Error> {
+    match op {
+        ConfigOp::Set { key, value } => config.set_str(key, value),
+        // `^$` matches no value that is set, so the value is appended.
+        ConfigOp::Add { key, value } => config.set_multivar(key, "^$", value),
+        ConfigOp::Remove { key } => {
+            if !values(config, key).is_empty() {
+                config.remove_multivar(key, ".*")?;
+            }
+            Ok(())
+        }
+        ConfigOp::RemoveValue { key, value } => {
+            if values(config, key).contains(value) {
+                config.remove_multivar(key, &format!("^{}$", escape_regex(value)))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if r"\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Every value of `key`, in the order git reads them.
+pub fn values(config: &Config, key: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Ok(mut entries) = config.multivar(key, None) {
+        while let Some(Ok(entry)) = entries.next() {
+            if let Some(value) = entry.value() {
+                values.push(value.to_string());
+            }
+        }
+    }
+    values
+}
+
+fn snapshot(config: &Config, ops: &[ConfigOp]) -> Vec<(String, Vec<String>)> {
+    let mut snapshot: Vec<(String, Vec<String>)> = Vec::new();
+    for op in ops {
+        if snapshot.iter().any(|(key, _)| key == op.key()) {
+            continue;
+        }
+        snapshot.push((op.key().to_string(), values(config, op.key())));
+    }
+    snapshot
+}
+
+fn restore(config: &mut Config, snapshot: &[(String, Vec<String>)]) {
+    for (key, previous) in snapshot {
+        let mut result = apply(config, &ConfigOp::Remove { key: key.clone() });
+        for value in previous {
+            result = result.and_then(|_| config.set_multivar(key, "^$", value));
+        }
+        if let Err(err) = result {
+            error!("Failed to restore git config key {}: {}", key, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// A transaction writing straight to the config of a scratch repository
+    /// in `dir`.
+    fn local_transaction(dir: &TempDir) -> ConfigTransaction {
+        git2::Repository::init(dir.path()).unwrap();
+        ConfigTransaction::new(true).with_local(Some(dir.path().to_path_buf()))
+    }
+
+    #[test]
+    fn proxy_paths_with_spaces_and_dots_round_trip() {
+        let dir = TempDir::new("proxy-spaces");
+        let mut transaction = local_transaction(&dir);
+        let url = "https://github.com/apple/swift-log.git";
+
+        for path in [
+            "/Users/Jane Doe/My Projects/checkouts/swift-log",
+            "/Users/j.doe/checkouts/swift-log.v2",
+        ] {
+            let key = proxy_key_with(path, INSTEAD_OF);
+            transaction.set(&key, url).unwrap();
+
+            let config = open(Some(dir.path())).unwrap();
+            assert_eq!(values(&config, &key), [url]);
+            let entries = proxy_entries(&config).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].path, Path::new(path));
+            assert_eq!(entries[0].url, url);
+
+            transaction.remove(&key).unwrap();
+            let config = open(Some(dir.path())).unwrap();
+            assert!(values(&config, &key).is_empty());
+            assert!(proxy_entries(&config).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn fragments_quote_proxy_paths_with_spaces() {
+        let path = "/Users/Jane Doe/checkouts/swift-log";
+        let fragment = render_fragment(&[ConfigOp::Set {
+            key: proxy_key_with(path, INSTEAD_OF),
+            value: String::from("https://github.com/apple/swift-log.git"),
+        }]);
+        assert_eq!(
+            fragment,
+            "[url \"/Users/Jane Doe/checkouts/swift-log\"]\n\tinsteadOf = https://github.com/apple/swift-log.git\n"
+        );
+
+        let dir = TempDir::new("proxy-fragment");
+        let file = dir.join("gitconfig");
+        std::fs::write(&file, &fragment).unwrap();
+        let config = Config::open(&file).unwrap();
+        assert_eq!(proxy_entries(&config).unwrap()[0].path, Path::new(path));
+    }
+
+    /// Set for the child processes started by the tests below.
+    const CHILD: &str = "SPM_GIT_SWAP_TEST_CHILD";
+
+    #[test]
+    fn open_default_writes_to_git_config_global() {
+        let dir = TempDir::new("git-config-global");
+        let file = dir.join("gitconfig");
+        std::fs::write(&file, "").unwrap();
+        std::fs::create_dir(dir.join("home")).unwrap();
+
+        // The environment is shared by every test thread, so it is only
+        // changed in a child process.
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "git_config::tests::write_through_open_default"])
+            .env(CHILD, "1")
+            .env("GIT_CONFIG_GLOBAL", &file)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("HOME", dir.join("home"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+
+        let config = Config::open(&file).unwrap();
+        assert_eq!(
+            values(&config, "url./tmp/checkout.insteadOf"),
+            ["https://example.com/repo.git"]
+        );
+        assert!(!dir.join("home/.gitconfig").exists());
+    }
+
+    /// Only does anything in the child process started by the test above.
+    #[test]
+    fn write_through_open_default() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+        let mut config = open_default().unwrap();
+        config
+            .set_str(
+                "url./tmp/checkout.insteadOf",
+                "https://example.com/repo.git",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn isolated_config_leaves_the_global_config_alone() {
+        let dir = TempDir::new("isolated-config");
+        let file = dir.join("gitconfig");
+        let sentinel = "[user]\n\tname = Sentinel\n";
+        std::fs::write(&file, sentinel).unwrap();
+        std::fs::create_dir(dir.join("home")).unwrap();
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "git_config::tests::write_through_isolated_config"])
+            .env(CHILD, "1")
+            .env("GIT_CONFIG_GLOBAL", &file)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("HOME", dir.join("home"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+
+        assert_eq!(std::fs::read(&file).unwrap(), sentinel.as_bytes());
+        assert!(!dir.join("home/.gitconfig").exists());
+    }
+
+    /// Only does anything in the child process started by the test above.
+    #[test]
+    fn write_through_isolated_config() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+        let isolated = IsolatedConfig::new(false).unwrap();
+        let mut config = open_default().unwrap();
+        config
+            .set_str(
+                "url./tmp/checkout.insteadOf",
+                "https://example.com/repo.git",
+            )
+            .unwrap();
+        let config = open_default().unwrap();
+        assert_eq!(
+            values(&config, "url./tmp/checkout.insteadOf"),
+            ["https://example.com/repo.git"]
+        );
+        assert!(config.get_string("user.name").is_err());
+        drop(config);
+        drop(isolated);
+    }
+}
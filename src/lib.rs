@@ -0,0 +1,33 @@
+//! Clones the packages pinned by SwiftPM `Package.resolved` files and points
+//! the global git config at the local copies. `main.rs` is a thin command
+//! line wrapper around this library.
+
+pub mod checkout;
+pub mod diagnostics;
+pub mod env;
+pub mod git_config;
+pub mod manifest;
+pub mod output;
+pub mod repo;
+pub mod resolved;
+pub mod settings;
+pub mod stats;
+pub mod url;
+
+mod binary;
+mod bundle;
+mod cas;
+mod fs;
+mod lock;
+mod metrics;
+mod preflight;
+mod progress;
+mod script;
+mod signature;
+mod summary;
+#[cfg(test)]
+mod test_support;
+mod token;
+
+pub use repo::{PackageRepo, PackageRepoError};
+pub use resolved::{parse_all_recursive, v2};
@@ -0,0 +1,123 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use log::info;
+
+use crate::{repo::PackageRepoError, resolved::v2};
+
+pub const LOCK_FILE: &str = ".spm-git-swap.lock";
+
+/// Reads the lockfile stored in `dir`, if there is one.
+pub fn read(dir: &Path) -> Result<Option<v2::Resolved>, PackageRepoError> {
+    let path = dir.join(LOCK_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    info!("Reading lockfile: {}", path.display());
+    let contents = std::fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+/// Writes `pins` to the lockfile in `dir`, sorted by identity so the file
+/// diffs cleanly when committed.
+pub fn write(dir: &Path, pins: &[v2::Pin]) -> Result<(), PackageRepoError> {
+    let mut pins = pins.to_vec();
+    pins.sort_by(|a, b| a.identity.cmp(&b.identity));
+
+    let path = dir.join(LOCK_FILE);
+    info!("Writing lockfile: {}", path.display());
+    let lock = v2::Resolved { pins, version: 2 };
+    std::fs::write(path, serde_json::to_string_pretty(&lock)?)?;
+    Ok(())
+}
+
+/// Replaces the state of every pin that is recorded in the lockfile with the
+/// locked state. Pins the lockfile doesn't know about are left as resolved.
+pub fn apply(pins: Vec<v2::Pin>, lock: &v2::Resolved) -> Vec<v2::Pin> {
+    let locked: HashMap<&str, &v2::Pin> = lock
+        .pins
+        .iter()
+        .map(|pin| (pin.identity.as_str(), pin))
+        .collect();
+
+    pins.into_iter()
+        .map(|pin| match locked.get(pin.identity.as_str()) {
+            Some(locked) => {
+                if locked.state != pin.state {
+                    info!(
+                        "Using locked revision {} for {} instead of {}",
+                        locked.state.revision, pin.identity, pin.state.revision
+                    );
+                }
+                (*locked).clone()
+            }
+            None => {
+                info!(
+                    "{} is not in the lockfile, using the resolved revision",
+                    pin.identity
+                );
+                pin
+            }
+        })
+        .collect()
+}
+
+/// How a pin differs from the previous lockfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(v2::Pin),
+    Removed(v2::Pin),
+    Bumped { from: v2::Pin, to: v2::Pin },
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added(pin) => write!(f, "{} added at {}", pin.identity, describe(&pin.state)),
+            Change::Removed(pin) => write!(f, "{} removed", pin.identity),
+            Change::Bumped { from, to } => write!(
+                f,
+                "{} bumped from {} \u{2192} {}",
+                to.identity,
+                describe(&from.state),
+                describe(&to.state)
+            ),
+        }
+    }
+}
+
+fn describe(state: &v2::State) -> &str {
+    state.version.as_deref().unwrap_or(&state.revision)
+}
+
+/// Lists the pins that were added, removed or moved to a different revision
+/// compared to `previous`, sorted by identity.
+pub fn changes(previous: &v2::Resolved, pins: &[v2::Pin]) -> Vec<Change> {
+    let before: BTreeMap<&str, &v2::Pin> = previous
+        .pins
+        .iter()
+        .map(|pin| (pin.identity.as_str(), pin))
+        .collect();
+    let after: BTreeMap<&str, &v2::Pin> =
+        pins.iter().map(|pin| (pin.identity.as_str(), pin)).collect();
+
+    let mut identities: Vec<&str> = before.keys().chain(after.keys()).copied().collect();
+    identities.sort_unstable();
+    identities.dedup();
+
+    identities
+        .into_iter()
+        .filter_map(|identity| match (before.get(identity), after.get(identity)) {
+            (None, Some(pin)) => Some(Change::Added((*pin).clone())),
+            (Some(pin), None) => Some(Change::Removed((*pin).clone())),
+            (Some(from), Some(to)) if from.state != to.state => Some(Change::Bumped {
+                from: (*from).clone(),
+                to: (*to).clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,25 +1,694 @@
-use repo::PackageRepo;
+use std::io::{IsTerminal, Write};
+
+use log::{warn, LevelFilter};
 use simple_logger::SimpleLogger;
+use spm_git_swap::{
+    checkout::CheckoutBy,
+    diagnostics::DiagnosticsFormat,
+    env,
+    git_config::{IsolatedConfig, KeyStyle},
+    output::{self, OutputFormat},
+    repo::{InsecurePolicy, InstallOptions},
+    resolved::{self, DedupeStrategy},
+    settings::Settings,
+    stats,
+    url::UrlRewriter,
+    PackageRepo, PackageRepoError,
+};
 use structopt::StructOpt;
 
-mod repo;
-mod resolved;
-
 /// A utility to clone repositories from .resolved files and update Git config.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "spm-git-swap")]
-enum Opt {
+struct Opt {
+    /// Show per-package details instead of a single status line. Repeat
+    /// for debug (`-vv`) and trace (`-vvv`) logging.
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// Only log warnings and errors. Repeat to only log errors (`-qq`) or
+    /// nothing at all (`-qqq`).
+    #[structopt(
+        short,
+        long,
+        parse(from_occurrences),
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    quiet: u8,
+
+    /// Fail unless libgit2 was built with this feature: `https`, `ssh`,
+    /// `threads` or `nsec`. Can be repeated.
+    #[structopt(
+        long,
+        global = true,
+        number_of_values = 1,
+        possible_values = &["https", "ssh", "threads", "nsec"]
+    )]
+    require_feature: Vec<String>,
+
+    /// The directory packages are stored in. Overrides `REPO_DIR`, which
+    /// in turn defaults to `swifter-package-manager` in the current
+    /// directory.
+    #[structopt(long, global = true, parse(from_os_str))]
+    repo_dir: Option<std::path::PathBuf>,
+
+    /// Keep bare clones in this content-addressed store, shared with other
+    /// tools, instead of the checkouts directory. Each repository is stored
+    /// at `<dir>/<key[..2]>/<key>.git`, where the key is the hex SHA-256 of
+    /// its URL without a trailing `/` or `.git`.
+    #[structopt(long, global = true, parse(from_os_str))]
+    cas_dir: Option<std::path::PathBuf>,
+
+    /// Keep the `insteadOf` entries in the config of the git repository at
+    /// this path instead of the global config, so they only apply to that
+    /// project.
+    #[structopt(long, global = true, parse(from_os_str))]
+    local: Option<std::path::PathBuf>,
+
+    /// Authenticate ssh remotes with this private key, such as a deploy
+    /// key, instead of the ssh agent and `~/.ssh/id_*`. Defaults to the key
+    /// `GIT_SSH_COMMAND` passes with `-i`.
+    #[structopt(long, global = true, parse(from_os_str))]
+    ssh_key: Option<std::path::PathBuf>,
+
+    /// Output format of `install`, `list` and `status`: `text`, or `json`
+    /// (also `jsonl`) to print one JSON object per package to stdout as
+    /// soon as it is known. Logs stay on stderr.
+    #[structopt(long, global = true, default_value = "text")]
+    format: OutputFormat,
+
+    /// Don't lock the repo directory during `install` and `wipe`. Only for
+    /// when the lock can't be taken, such as on a file system without
+    /// locking; concurrent runs can then corrupt checkouts and git config.
+    #[structopt(long, global = true)]
+    no_lock: bool,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
 
+// Parsed once per run, so the size of `Install` doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+enum Command {
     /// Install packages from .resolved files.
-    Install {
-         /// The path to scan for .resolved files.
+    Install(InstallArgs),
+
+    /// Fetch and check out the pinned revision of every package that is
+    /// already installed, without cloning the ones that aren't. Takes the
+    /// same options as `install`, and always implies `--update-lock`.
+    Update(InstallArgs),
+
+    /// Create the repo directory and a starter config.toml.
+    Init {
+        /// The repo directory to create. Defaults to `--repo-dir`, then
+        /// `REPO_DIR`.
+        #[structopt(parse(from_os_str))]
+        dir: Option<std::path::PathBuf>,
+    },
+
+    /// List the packages pinned by .resolved files, or, without a path, the
+    /// urls the git config currently redirects to checkouts.
+    List {
+        /// The path to scan for .resolved files, or a single resolved file.
+        #[structopt(parse(from_os_str))]
+        path: Option<std::path::PathBuf>,
+
+        /// Summarize the pins by host, kind and versioning instead of
+        /// listing them.
+        #[structopt(long, requires = "path")]
+        stats: bool,
+
+        /// Print the output as JSON.
+        #[structopt(long)]
+        json: bool,
+
+        /// Print `export SPM_PIN_<IDENTITY>=<revision>` lines for shell
+        /// scripts and Makefiles to source.
+        #[structopt(long, requires = "path", conflicts_with_all = &["stats", "json"])]
+        env: bool,
+
+        #[structopt(flatten)]
+        dedupe: DedupeArgs,
+
+        /// Number of threads reading and parsing the resolved files found
+        /// under the path.
+        #[structopt(long, default_value = "1")]
+        scan_jobs: usize,
+    },
+
+    /// Check that the checkout of every git package pinned by the .resolved
+    /// files is at its pinned revision and swapped in by the git config.
+    Status {
+        /// The path to scan for .resolved files, or a single resolved file.
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+    },
+
+    /// Print the clone URL and checkout path of every git package, one per
+    /// line, for use with an external downloader.
+    ExportUrls {
+        /// The path to scan for .resolved files, or a single resolved file.
         #[structopt(parse(from_os_str))]
         path: std::path::PathBuf,
+
+        /// A JSON or TOML file mapping package identities or locations to
+        /// alternate clone URLs.
+        #[structopt(long, parse(from_os_str))]
+        url_map: Option<std::path::PathBuf>,
+
+        #[structopt(flatten)]
+        ssh: SshArgs,
     },
 
     /// Wipe cached repositories.
-    Wipe
-   
+    Wipe {
+        /// Don't ask for confirmation. Required when stdin is not a
+        /// terminal.
+        #[structopt(short, long)]
+        yes: bool,
+
+        /// Only remove the checkout of the package with this identity, and
+        /// the git config entries pointing at it.
+        #[structopt(long)]
+        package: Option<String>,
+    },
+
+    /// Remove the git config entries redirecting to the checkouts, keeping
+    /// the checkouts themselves.
+    Unswap,
+
+    /// Redirect the origin url of every checkout on disk to it again.
+    Swap,
+
+    /// List the checkouts that no pin in the resolved files under a path
+    /// uses any more.
+    Orphans {
+        /// The path to scan for .resolved files, or a single resolved file.
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+
+        /// Delete the orphaned checkouts and their git config entries.
+        #[structopt(long)]
+        remove: bool,
+
+        /// Print the output as JSON.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Delete the checkouts that no pin in the resolved files under a path
+    /// uses any more, along with their git config entries.
+    Gc {
+        /// The path to scan for .resolved files, or a single resolved file.
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+
+        /// Only list what would be deleted and the space it takes up.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Merge the pins of every .resolved file under a path into a single
+    /// version 2 resolved file.
+    Merge {
+        /// The path to scan for .resolved files, or a single resolved file.
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+
+        /// Where to write the merged file. Printed to stdout when omitted.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<std::path::PathBuf>,
+
+        #[structopt(flatten)]
+        dedupe: DedupeArgs,
+    },
+
+    /// Export the current state of the cache as a JSON manifest.
+    Manifest {
+        /// Where to write the manifest. Printed to stdout when omitted.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Check that every git config entry pointing into the repo directory
+    /// leads to a checkout with a valid HEAD whose remote matches the
+    /// redirected url. Fails if any of them is broken.
+    Verify {
+        /// Instead, check the cache against a previously exported manifest.
+        #[structopt(long, parse(from_os_str))]
+        against: Option<std::path::PathBuf>,
+
+        /// Check that the commit at every revision pinned by the .resolved
+        /// files under this path carries a valid gpg signature.
+        #[structopt(long, parse(from_os_str))]
+        signatures: Option<std::path::PathBuf>,
+
+        /// The gpg keyring holding the trusted signing keys. Defaults to
+        /// the user's keyring.
+        #[structopt(long, parse(from_os_str), requires = "signatures")]
+        keyring: Option<std::path::PathBuf>,
+
+        /// Remove the entries whose checkout is gone or isn't a git
+        /// repository.
+        #[structopt(long, conflicts_with_all = &["against", "signatures"])]
+        fix: bool,
+    },
+
+    /// Pack the checkouts as git bundles into a single archive that can be
+    /// restored on another machine without network access.
+    Bundle {
+        /// The zip archive to write. Tar names such as `cache.tar` are
+        /// refused.
+        #[structopt(parse(from_os_str))]
+        out: std::path::PathBuf,
+    },
+
+    /// Restore checkouts from an archive written by `bundle` and point the
+    /// git config at them.
+    Unbundle {
+        /// The archive to read.
+        #[structopt(parse(from_os_str))]
+        archive: std::path::PathBuf,
+    },
+
+    /// Move a repo directory, with its checkouts, binary targets, settings
+    /// and manifest, and update the git config to point at the new location.
+    Relocate {
+        /// The current repo directory.
+        #[structopt(parse(from_os_str))]
+        old: std::path::PathBuf,
+
+        /// The new repo directory.
+        #[structopt(parse(from_os_str))]
+        new: std::path::PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct InstallArgs {
+    /// The path to scan for .resolved files, or a single resolved file.
+    #[structopt(parse(from_os_str))]
+    path: std::path::PathBuf,
+
+    /// Write git config changes as each package is processed instead of
+    /// applying them all at once at the end of the run.
+    #[structopt(long)]
+    no_transaction: bool,
+
+    /// Use a temporary global git config for this run instead of the user's
+    /// own. The `insteadOf` entries it creates are removed with it when the
+    /// run ends, unless `--keep-isolated` is given.
+    #[structopt(long)]
+    isolated: bool,
+
+    /// Keep the temporary git config of an `--isolated` run and log where it
+    /// is.
+    #[structopt(long, requires = "isolated")]
+    keep_isolated: bool,
+
+    /// Check that every host can be reached with the available
+    /// credentials before cloning anything.
+    #[structopt(long)]
+    preflight: bool,
+
+    /// Timeout in seconds for network operations. A clone or fetch whose
+    /// remote sends nothing for this long is abandoned and its package
+    /// reported as timed out, while the other packages carry on.
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Git protocol version to request when fetching. Only applies to
+    /// http(s) remotes; ssh remotes use the library default.
+    #[structopt(long, possible_values = &["0", "1", "2"])]
+    git_protocol: Option<u8>,
+
+    /// Print the resulting git config fragment to stdout instead of
+    /// writing it to the global git config.
+    #[structopt(long)]
+    print_config: bool,
+
+    /// Replace directories in the way of a checkout that aren't a git
+    /// repository, and `insteadOf` values for a checkout path that
+    /// spm-git-swap didn't write instead of keeping them next to its own.
+    #[structopt(long)]
+    force: bool,
+
+    /// Update the modification time of every reused checkout.
+    #[structopt(long)]
+    touch: bool,
+
+    /// A JSON or TOML file mapping package identities or locations to
+    /// alternate clone URLs, e.g. internal mirrors.
+    #[structopt(long, parse(from_os_str))]
+    url_map: Option<std::path::PathBuf>,
+
+    /// Clone `<upstream-url>` from `<fork-url>` instead, while the git config
+    /// keeps redirecting the upstream URL to the checkout. Can be repeated
+    /// and takes precedence over `--url-map`.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_replacement))]
+    replace: Vec<(String, String)>,
+
+    #[structopt(flatten)]
+    ssh: SshArgs,
+
+    /// Borrow objects from a shared bare mirror when cloning packages hosted
+    /// on `<host>`, like `git clone --reference`. Only missing objects are
+    /// downloaded. Checkouts made this way break if the mirror is removed.
+    /// Can be repeated.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_host_mirror))]
+    host_mirror: Vec<(String, std::path::PathBuf)>,
+
+    /// Which git config keys to write: `insteadOf` to redirect fetches,
+    /// `pushInsteadOf` to redirect pushes, or `both`.
+    #[structopt(long)]
+    config_key_style: Option<KeyStyle>,
+
+    /// Don't clone or fetch anything, only point the git config at
+    /// checkouts that already exist.
+    #[structopt(long)]
+    offline: bool,
+
+    /// Reuse repositories from SwiftPM's own cache directory when they
+    /// already contain the pinned revision instead of cloning them again.
+    #[structopt(long, parse(from_os_str))]
+    reuse_spm_cache: Option<std::path::PathBuf>,
+
+    /// Abort if the filesystem holding the checkouts has fewer than this
+    /// many bytes free before a clone or fetch.
+    #[structopt(long)]
+    min_free_space: Option<u64>,
+
+    /// Regenerate `.spm-git-swap.lock` from the resolved files instead of
+    /// preferring the revisions it records.
+    #[structopt(long)]
+    update_lock: bool,
+
+    /// Proxy to use for http(s) remotes, or `auto` to use git's
+    /// `http.proxy` setting. Defaults to `HTTPS_PROXY` or `ALL_PROXY`.
+    #[structopt(long)]
+    http_proxy: Option<String>,
+
+    /// Name of the remote created when cloning (default `origin`). Useful
+    /// when tooling that inspects the local checkouts expects the remote
+    /// to carry a particular name.
+    #[structopt(long)]
+    remote_name: Option<String>,
+
+    /// Overall time limit in seconds for the whole install. Packages not
+    /// reached in time are skipped and the command exits with code 4.
+    #[structopt(long)]
+    deadline: Option<u64>,
+
+    /// Write Prometheus textfile metrics about the run to this file.
+    #[structopt(long, parse(from_os_str))]
+    metrics_file: Option<std::path::PathBuf>,
+
+    /// Write the summary printed at the end of the run, with the error of
+    /// every failed package, to this file as JSON.
+    #[structopt(long, parse(from_os_str))]
+    summary_json: Option<std::path::PathBuf>,
+
+    /// Update the remote of existing checkouts to the URL a fresh clone would
+    /// use. By default existing checkouts keep fetching from the URL, and
+    /// protocol, they were cloned with.
+    #[structopt(long)]
+    rewrite_existing_remotes: bool,
+
+    /// Also write the pins whose revision changed since the previous
+    /// lockfile to this file, one per line.
+    #[structopt(long, parse(from_os_str))]
+    changed_report: Option<std::path::PathBuf>,
+
+    /// How each checkout is moved to the pinned revision: `revision`
+    /// detaches HEAD at the commit, `version` at the version's tag so `git
+    /// describe` reports it, and `branch` puts HEAD on the pinned branch.
+    #[structopt(
+        long,
+        default_value = "revision",
+        possible_values = &["revision", "version", "branch"]
+    )]
+    checkout_by: CheckoutBy,
+
+    /// Instead of installing, check that every package's clone URL is
+    /// reachable and advertises the pinned revision. Nothing is written to
+    /// disk or to the git config.
+    #[structopt(long)]
+    check: bool,
+
+    /// Refuse packages pinned to a plain `http://` URL. `skip` leaves them
+    /// out with a warning instead of failing the install.
+    #[structopt(long, possible_values = &["reject", "skip"])]
+    require_https: Option<Option<InsecurePolicy>>,
+
+    /// Shell command run in each checkout after it is cloned, fetched or
+    /// linked, with `SPM_GIT_SWAP_IDENTITY`, `SPM_GIT_SWAP_LOCATION`,
+    /// `SPM_GIT_SWAP_PATH` and `SPM_GIT_SWAP_REVISION` set. A non-zero exit
+    /// marks the package as failed.
+    #[structopt(long)]
+    checkout_hook: Option<String>,
+
+    /// Only install from the Package.resolved files that changed relative to
+    /// this git ref, e.g. `origin/main`. Falls back to scanning everything
+    /// when the diff can't be computed.
+    #[structopt(long)]
+    changed_files_from: Option<String>,
+
+    /// How problems found in the resolved files are reported: `human` log
+    /// lines, `github` workflow commands that show up as annotations on the
+    /// pull request, or one `json` object per line on stdout.
+    #[structopt(
+        long,
+        default_value = "human",
+        possible_values = &["human", "github", "json"]
+    )]
+    diagnostics_format: DiagnosticsFormat,
+
+    #[structopt(flatten)]
+    dedupe: DedupeArgs,
+
+    /// Fail when resolved files pin the same package to different
+    /// revisions instead of warning. Same as `--dedupe-strategy error`.
+    #[structopt(long, conflicts_with = "dedupe-strategy")]
+    strict: bool,
+
+    /// Only fetch existing checkouts last fetched longer ago than this, such
+    /// as `12h` or `7d`, and check them out at the pinned revision. Newer
+    /// ones that already have the revision are reused as is.
+    #[structopt(long, parse(try_from_str = parse_age))]
+    refresh_older_than: Option<std::time::Duration>,
+
+    /// Fetch existing checkouts even when they already contain the pinned
+    /// revision. By default only checkouts missing it are fetched.
+    #[structopt(long, conflicts_with = "refresh-older-than")]
+    force_fetch: bool,
+
+    /// Write the equivalent `git clone`, `git fetch`, `git checkout` and `git
+    /// config` commands to this shell script instead of running them, for
+    /// review or to run by hand. Nothing is cloned and no config or lockfile
+    /// is written.
+    #[structopt(long, parse(from_os_str))]
+    emit_script: Option<std::path::PathBuf>,
+
+    /// Number of threads reading and parsing the resolved files found under
+    /// the path.
+    #[structopt(long, default_value = "1")]
+    scan_jobs: usize,
+
+    /// Number of packages cloned or fetched at the same time. Defaults to
+    /// the number of logical CPUs.
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+
+    /// Print what would be cloned, fetched, linked or skipped, and the git
+    /// config entries that would be written, without doing any of it.
+    #[structopt(long, conflicts_with_all = &["check", "emit-script", "print-config"])]
+    dry_run: bool,
+
+    /// Only install packages whose identity matches this glob, such as
+    /// `my-org-*`. Can be repeated to install packages matching any of them.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = glob::Pattern::new))]
+    filter: Vec<glob::Pattern>,
+
+    /// Skip packages whose identity matches this glob, applied after
+    /// `--filter`. Can be repeated.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = glob::Pattern::new))]
+    exclude: Vec<glob::Pattern>,
+
+    /// Make shallow clones with only this many commits of history, to save
+    /// time and disk. The pinned revision is fetched by id when it isn't
+    /// within the depth, which only some servers allow.
+    #[structopt(long)]
+    depth: Option<u32>,
+
+    /// Store checkouts as bare repositories. SwiftPM clones from them just
+    /// the same, without a work tree taking up disk.
+    #[structopt(long)]
+    bare: bool,
+
+    /// Retry a clone or fetch this many times after a network error, waiting
+    /// 1s, 2s, 4s and so on between attempts. Authentication and not found
+    /// errors fail straight away.
+    #[structopt(long, default_value = "2")]
+    retries: u32,
+
+    /// Exit successfully even when some packages failed to install. Their
+    /// failures are still logged and reported.
+    #[structopt(long)]
+    keep_going: bool,
+
+    /// Stop starting new packages as soon as one fails, including when its
+    /// checkout hook fails. Packages already being installed still finish.
+    #[structopt(long, conflicts_with = "keep-going")]
+    fail_fast: bool,
+}
+
+// The `--dedupe-strategy` option of every command that merges resolved
+// files. Not a doc comment, which would replace the help of the commands it
+// is flattened into.
+#[derive(StructOpt, Debug)]
+struct DedupeArgs {
+    /// Which pin wins when files pin the same package differently:
+    /// `versioned` prefers a pin with a version and then the first
+    /// scanned, `last` or `first` go by scan order, `highest-version`
+    /// picks the greatest version and `error` fails listing every conflict.
+    #[structopt(
+        long,
+        default_value = "versioned",
+        possible_values = &["versioned", "last", "first", "highest-version", "error"]
+    )]
+    dedupe_strategy: DedupeStrategy,
+}
+
+// The options of every command that decides which locations are cloned
+// over ssh. Not a doc comment, for the same reason as `DedupeArgs`.
+#[derive(StructOpt, Debug)]
+struct SshArgs {
+    /// Clone https locations on `<host>` over ssh as `<user>@<host>:<path>`,
+    /// in addition to github.com. Can be repeated, or given as a
+    /// comma-separated list in `SPM_GIT_SWAP_SSH_HOSTS`.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        env = "SPM_GIT_SWAP_SSH_HOSTS",
+        use_delimiter = true,
+        parse(try_from_str = parse_ssh_host)
+    )]
+    ssh_host: Vec<(String, String)>,
+
+    /// Keep https locations as written instead of converting any of them to
+    /// ssh, for example when authenticating over https with a credential
+    /// helper.
+    #[structopt(long, conflicts_with = "ssh-host")]
+    no_ssh: bool,
+}
+
+impl SshArgs {
+    /// Adds the ssh hosts to `url_rewriter`, or stops it from converting
+    /// anything to ssh.
+    fn apply(self, url_rewriter: UrlRewriter) -> UrlRewriter {
+        let url_rewriter = url_rewriter.with_ssh_hosts(self.ssh_host);
+        if self.no_ssh {
+            url_rewriter.without_ssh()
+        } else {
+            url_rewriter
+        }
+    }
+}
+
+impl InstallArgs {
+    fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1)
+    }
+
+    /// Builds the install options, falling back to `settings` for anything
+    /// that wasn't given on the command line.
+    fn into_options(
+        self,
+        settings: Settings,
+        verbose: bool,
+        format: OutputFormat,
+    ) -> Result<InstallOptions, Box<dyn std::error::Error>> {
+        let jobs = self.jobs();
+        let url_map = self.url_map.or(settings.url_map);
+        let url_rewriter = self
+            .ssh
+            .apply(url_rewriter(url_map.as_deref())?.with_replacements(self.replace));
+        let key_style = match self.config_key_style {
+            Some(key_style) => key_style,
+            None => settings
+                .config_key_style
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(PackageRepoError::Settings)?
+                .unwrap_or_default(),
+        };
+
+        Ok(InstallOptions {
+            no_transaction: self.no_transaction,
+            preflight: self.preflight,
+            timeout: self
+                .timeout
+                .or(settings.timeout)
+                .map(std::time::Duration::from_secs),
+            verbose,
+            git_protocol: self.git_protocol.or(settings.git_protocol),
+            print_config: self.print_config,
+            force: self.force,
+            format,
+            touch: self.touch,
+            url_rewriter,
+            key_style,
+            offline: self.offline,
+            existing_only: false,
+            reuse_spm_cache: self.reuse_spm_cache.or(settings.reuse_spm_cache),
+            min_free_space: self.min_free_space.or(settings.min_free_space),
+            update_lock: self.update_lock,
+            http_proxy: self
+                .http_proxy
+                .or(settings.http_proxy)
+                .or_else(proxy_from_env),
+            remote_name: self.remote_name.or(settings.remote_name),
+            deadline: self.deadline.map(std::time::Duration::from_secs),
+            metrics_file: self.metrics_file,
+            summary_json: self.summary_json,
+            rewrite_existing_remotes: self.rewrite_existing_remotes,
+            changed_report: self.changed_report,
+            checkout_by: self.checkout_by,
+            check: self.check,
+            require_https: self.require_https.map(Option::unwrap_or_default),
+            checkout_hook: self.checkout_hook,
+            changed_files_from: self.changed_files_from,
+            diagnostics_format: self.diagnostics_format,
+            dedupe_strategy: if self.strict {
+                DedupeStrategy::Error
+            } else {
+                self.dedupe.dedupe_strategy
+            },
+            refresh_older_than: self.refresh_older_than,
+            force_fetch: self.force_fetch,
+            emit_script: self.emit_script,
+            scan_jobs: self.scan_jobs,
+            jobs,
+            dry_run: self.dry_run,
+            filters: self.filter,
+            excludes: self.exclude,
+            depth: self.depth,
+            bare: self.bare,
+            retries: self.retries,
+            keep_going: self.keep_going,
+            fail_fast: self.fail_fast,
+            host_mirrors: self
+                .host_mirror
+                .into_iter()
+                .map(|(host, mirror)| (host.to_lowercase(), mirror))
+                .collect(),
+        })
+    }
 }
 
 fn main() {
@@ -27,24 +696,406 @@ fn main() {
 
     if let Err(e) = run(opt) {
         eprintln!("Error: {}", e);
-        std::process::exit(1);
+        let code = e
+            .downcast_ref::<PackageRepoError>()
+            .map_or(1, PackageRepoError::exit_code);
+        std::process::exit(code);
     }
 }
 
 fn run(opt: Opt) -> Result<(), Box<dyn std::error::Error>> {
-    SimpleLogger::new().init().unwrap();
+    // Packages installed in parallel log from threads named after them.
+    let parallel = matches!(
+        &opt.command,
+        Command::Install(args) | Command::Update(args) if args.jobs() > 1
+    );
+    // `RUST_LOG` picks the level unless -v or -q was given.
+    let logger = match log_level(opt.verbose, opt.quiet) {
+        Some(level) => SimpleLogger::new().with_level(level),
+        None => SimpleLogger::new().with_level(LevelFilter::Info).env(),
+    };
+    logger.with_threads(parallel).init().unwrap();
+    require_features(&opt.require_feature)?;
+    let repo_dir = opt.repo_dir;
+    let cas_dir = opt.cas_dir;
+    let local = opt.local;
+    let ssh_key = opt.ssh_key;
+    let format = opt.format;
+    let existing_only = matches!(opt.command, Command::Update(_));
 
-    let mut package_repo = PackageRepo::new()?;
-    
-    match opt {
-        Opt::Install { path } => {
-            package_repo.install(&path)?;
-        
+    match opt.command {
+        Command::Install(args) | Command::Update(args) => {
+            let repo_dir = resolve_repo_dir(repo_dir)?;
+            let settings = Settings::load(&repo_dir)?;
+            let cas_dir = cas_dir.or_else(|| settings.cas_dir.clone());
+            let ssh_key = ssh_key.or_else(|| settings.ssh_key.clone());
+            let path = args.path.clone();
+            let _isolated = if args.isolated {
+                Some(IsolatedConfig::new(args.keep_isolated)?)
+            } else {
+                None
+            };
+            let mut options = args.into_options(settings, opt.verbose > 0, format)?;
+            options.existing_only = existing_only;
+            let repo = PackageRepo::new(&repo_dir)?
+                .with_cas_dir(cas_dir)
+                .with_ssh_key(ssh_key)
+                .with_local_config(local);
+            lock(repo, opt.no_lock)?.install(&path, &options)?;
+        },
+        Command::Init { dir } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => resolve_repo_dir(repo_dir)?,
+            };
+            PackageRepo::init(&dir)?;
+        },
+        Command::List {
+            path,
+            stats,
+            json,
+            env,
+            dedupe,
+            scan_jobs,
+        } => {
+            let Some(path) = path else {
+                let swapped = open_repo(repo_dir, cas_dir, local, ssh_key)?.swapped()?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&swapped)?);
+                } else if format == OutputFormat::JsonLines {
+                    for swap in &swapped {
+                        output::emit(swap);
+                    }
+                } else {
+                    for swap in &swapped {
+                        let head = match (&swap.head, swap.stale) {
+                            (_, true) => "stale",
+                            (Some(head), false) => head,
+                            (None, false) => "-",
+                        };
+                        println!("{}\t{}\t{}", swap.url, swap.path.display(), head);
+                    }
+                }
+                let stale = swapped.iter().filter(|swap| swap.stale).count();
+                if stale > 0 {
+                    warn!(
+                        "{} url(s) are redirected to checkouts that no longer exist. Run `wipe` or `unswap` to remove them",
+                        stale
+                    );
+                }
+                return Ok(());
+            };
+            let files = resolved::parse_files_with(&path, scan_jobs)?;
+            let pins = resolved::merge_with(files, dedupe.dedupe_strategy)?;
+            if stats {
+                let stats = stats::Stats::from_pins(&pins);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print!("{}", stats);
+                }
+            } else if json {
+                println!("{}", serde_json::to_string_pretty(&pins)?);
+            } else if format == OutputFormat::JsonLines {
+                for pin in &pins {
+                    output::emit(&output::ListEvent::new(pin));
+                }
+            } else if env {
+                for export in env::exports(&pins) {
+                    println!("{}", export);
+                }
+            } else {
+                for pin in pins {
+                    println!(
+                        "{}\t{}\t{}",
+                        pin.identity,
+                        pin.state.version.as_deref().unwrap_or(&pin.state.revision),
+                        pin.location
+                    );
+                }
+            }
+        },
+        Command::Status { path } => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.status(&path, format)?;
+        },
+        Command::ExportUrls {
+            path,
+            url_map,
+            ssh,
+        } => {
+            let url_rewriter = ssh.apply(url_rewriter(url_map.as_deref())?);
+            for (url, checkout) in open_repo(repo_dir, cas_dir, local, ssh_key)?.export_urls(&path, &url_rewriter)? {
+                println!("{}\t{}", url, checkout.display());
+            }
+        },
+        Command::Merge {
+            path,
+            output,
+            dedupe,
+        } => {
+            let pins =
+                resolved::merge_with(resolved::parse_files(&path)?, dedupe.dedupe_strategy)?;
+            let merged =
+                serde_json::to_string_pretty(&resolved::v2::Resolved { pins, version: 2 })?;
+            match output {
+                Some(output) => std::fs::write(output, merged)?,
+                None => println!("{}", merged),
+            }
+        },
+        Command::Wipe { yes, package } => {
+            let repo = open_repo(repo_dir, cas_dir, local, ssh_key)?;
+            if let Some(package) = package {
+                if !yes {
+                    confirm(&format!(
+                        "Delete the checkout of {} and its git config entries?",
+                        package
+                    ))?;
+                }
+                lock(repo, opt.no_lock)?.wipe_package(&package)?;
+                return Ok(());
+            }
+            if !yes {
+                let target = match repo.cas_dir() {
+                    Some(cas_dir) => format!(
+                        "{} and the checkouts it uses in {}",
+                        repo.checkouts_dir().display(),
+                        cas_dir.display()
+                    ),
+                    None => repo.checkouts_dir().display().to_string(),
+                };
+                confirm(&format!("Delete {} and their git config entries?", target))?;
+            }
+            lock(repo, opt.no_lock)?.wipe()?;
         },
-        Opt::Wipe => {
-            package_repo.wipe()?;
+        Command::Unswap => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.unswap()?;
+        },
+        Command::Swap => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.swap()?;
+        },
+        Command::Orphans { path, remove, json } => {
+            let mut repo = open_repo(repo_dir, cas_dir, local, ssh_key)?;
+            let orphans = repo.orphans(&path)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&orphans)?);
+            } else {
+                for orphan in &orphans {
+                    println!("{}\t{}", orphan.identity, orphan.path.display());
+                }
+            }
+            if remove {
+                repo.remove_orphans(&orphans)?;
+            }
+        },
+        Command::Gc { path, dry_run } => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.gc(&path, dry_run)?;
+        },
+        Command::Manifest { output } => {
+            let manifest = open_repo(repo_dir, cas_dir, local, ssh_key)?.manifest()?;
+            match output {
+                Some(output) => manifest.write(&output)?,
+                None => println!("{}", serde_json::to_string_pretty(&manifest)?),
+            }
+        },
+        Command::Verify {
+            against,
+            signatures,
+            keyring,
+            fix,
+        } => {
+            let repo = open_repo(repo_dir, cas_dir, local, ssh_key)?;
+            if against.is_none() && signatures.is_none() {
+                repo.verify_proxies(fix)?;
+            }
+            if let Some(against) = against {
+                repo.verify_against(&against)?;
+            }
+            if let Some(signatures) = signatures {
+                repo.verify_signatures(&signatures, keyring.as_deref())?;
+            }
+        },
+        Command::Bundle { out } => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.bundle(&out)?;
+        },
+        Command::Unbundle { archive } => {
+            open_repo(repo_dir, cas_dir, local, ssh_key)?.unbundle(&archive)?;
+        },
+        Command::Relocate { old, new } => {
+            PackageRepo::relocate(&old, &new, local.as_deref())?;
         },
     }
 
     Ok(())
 }
+
+/// The directory packages are stored in: `repo_dir` from `--repo-dir`, then
+/// `REPO_DIR`, then `swifter-package-manager` in the current directory.
+fn resolve_repo_dir(
+    repo_dir: Option<std::path::PathBuf>,
+) -> Result<std::path::PathBuf, PackageRepoError> {
+    if let Some(repo_dir) = repo_dir {
+        return Ok(repo_dir);
+    }
+    if let Ok(repo_dir) = std::env::var("REPO_DIR") {
+        return Ok(std::path::PathBuf::from(repo_dir));
+    }
+
+    let working_dir = std::env::current_dir()?;
+    warn!(
+        "REPO_DIR not set, using current directory({}/swifter-package-manager/checkouts) to store packages. Run `spm-git-swap init` to set up a permanent location",
+        working_dir.display()
+    );
+    Ok(working_dir.join("swifter-package-manager"))
+}
+
+fn url_rewriter(url_map: Option<&std::path::Path>) -> Result<UrlRewriter, PackageRepoError> {
+    let mut url_rewriter = UrlRewriter::default();
+    if let Some(url_map) = url_map {
+        url_rewriter = url_rewriter.with_url_map(UrlRewriter::load_url_map(url_map)?);
+    }
+    Ok(url_rewriter)
+}
+
+/// Opens the repo directory given by `repo_dir` or the environment, taking
+/// the store location from `cas_dir` or the settings file, with the proxy
+/// entries in the config of the `local` repository if given.
+fn open_repo(
+    repo_dir: Option<std::path::PathBuf>,
+    cas_dir: Option<std::path::PathBuf>,
+    local: Option<std::path::PathBuf>,
+    ssh_key: Option<std::path::PathBuf>,
+) -> Result<PackageRepo, PackageRepoError> {
+    let repo_dir = resolve_repo_dir(repo_dir)?;
+    let settings = Settings::load(&repo_dir)?;
+    Ok(PackageRepo::new(&repo_dir)?
+        .with_cas_dir(cas_dir.or(settings.cas_dir))
+        .with_ssh_key(ssh_key.or(settings.ssh_key))
+        .with_local_config(local))
+}
+
+/// Asks a yes/no question on stderr and fails unless it is answered with
+/// yes. Refuses without asking when stdin is not a terminal, so a script
+/// neither hangs nor deletes anything it didn't ask for with `--yes`.
+fn confirm(question: &str) -> Result<(), PackageRepoError> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(PackageRepoError::NotConfirmed(String::from(
+            "stdin is not a terminal, pass --yes to go ahead without asking",
+        )));
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(PackageRepoError::NotConfirmed(String::from("cancelled"))),
+    }
+}
+
+fn lock(repo: PackageRepo, no_lock: bool) -> Result<PackageRepo, PackageRepoError> {
+    if no_lock {
+        Ok(repo)
+    } else {
+        repo.locked()
+    }
+}
+
+fn require_features(features: &[String]) -> Result<(), PackageRepoError> {
+    let version = git2::Version::get();
+    for feature in features {
+        let available = match feature.as_str() {
+            "https" => version.https(),
+            "ssh" => version.ssh(),
+            "threads" => version.threads(),
+            "nsec" => version.nsec(),
+            _ => false,
+        };
+        if !available {
+            let (major, minor, patch) = version.libgit2_version();
+            return Err(PackageRepoError::MissingFeature(format!(
+                "libgit2 {}.{}.{} was built without {} support",
+                major, minor, patch, feature
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn parse_replacement(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((upstream, fork)) if !upstream.is_empty() && !fork.is_empty() => {
+            Ok((upstream.to_string(), fork.to_string()))
+        }
+        _ => Err(format!(
+            "invalid replacement {}, expected <upstream-url>=<fork-url>",
+            value
+        )),
+    }
+}
+
+fn parse_ssh_host(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((host, user)) if !host.is_empty() && !user.is_empty() => {
+            Ok((host.to_string(), user.to_string()))
+        }
+        _ => Err(format!("invalid ssh host {}, expected <host>=<user>", value)),
+    }
+}
+
+/// Parses an age such as `90s`, `30m`, `12h`, `7d` or `2w`. A bare number is
+/// in seconds.
+fn parse_age(value: &str) -> Result<std::time::Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => 0,
+    };
+    match amount.parse::<u64>() {
+        Ok(amount) if seconds > 0 => Ok(std::time::Duration::from_secs(amount * seconds)),
+        _ => Err(format!(
+            "invalid age {}, expected a number followed by s, m, h, d or w",
+            value
+        )),
+    }
+}
+
+fn parse_host_mirror(value: &str) -> Result<(String, std::path::PathBuf), String> {
+    match value.split_once('=') {
+        Some((host, mirror)) if !host.is_empty() && !mirror.is_empty() => {
+            Ok((host.to_string(), std::path::PathBuf::from(mirror)))
+        }
+        _ => Err(format!(
+            "invalid host mirror {}, expected <host>=<bare-dir>",
+            value
+        )),
+    }
+}
+
+/// The log level asked for with -v or -q, with -v adding per-package
+/// details at the default level.
+fn log_level(verbose: u8, quiet: u8) -> Option<LevelFilter> {
+    match (verbose, quiet) {
+        (0, 0) => None,
+        (1, 0) => Some(LevelFilter::Info),
+        (2, 0) => Some(LevelFilter::Debug),
+        (_, 0) => Some(LevelFilter::Trace),
+        (_, 1) => Some(LevelFilter::Warn),
+        (_, 2) => Some(LevelFilter::Error),
+        _ => Some(LevelFilter::Off),
+    }
+}
+
+fn proxy_from_env() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+}
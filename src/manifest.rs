@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fs::write_atomic, repo::PackageRepoError};
+
+/// Version 2 added the pinned `revision` and `version` of each checkout.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Name of the manifest `install` keeps in the repo directory.
+pub const INSTALLED_FILE: &str = "manifest.json";
+
+/// A snapshot of the checkouts in the cache and the proxies pointing at them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub schema_version: u32,
+    pub checkouts: Vec<Checkout>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Checkout {
+    pub identity: String,
+    pub path: PathBuf,
+    /// The url of the checkout's origin remote.
+    pub location: Option<String>,
+    /// The commit the checkout's HEAD points at.
+    pub head: Option<String>,
+    /// The url redirected to this checkout by the git config.
+    pub proxy: Option<String>,
+    /// The revision the resolved files pin, when `install` checked it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The version the resolved files pin, if it is a tagged release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// A url the git config redirects into the cache.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Swapped {
+    pub url: String,
+    pub path: PathBuf,
+    /// The commit the checkout's HEAD points at.
+    pub head: Option<String>,
+    /// Whether the checkout the url is redirected to no longer exists.
+    pub stale: bool,
+}
+
+/// A difference between two manifests for a single identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    Added(String),
+    Removed(String),
+    HeadChanged {
+        identity: String,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    ProxyChanged {
+        identity: String,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |value: &Option<String>| value.clone().unwrap_or_else(|| "none".to_string());
+        match self {
+            Drift::Added(identity) => write!(f, "{}: added", identity),
+            Drift::Removed(identity) => write!(f, "{}: removed", identity),
+            Drift::HeadChanged {
+                identity,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: HEAD changed from {} to {}",
+                identity,
+                describe(expected),
+                describe(actual)
+            ),
+            Drift::ProxyChanged {
+                identity,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: proxy changed from {} to {}",
+                identity,
+                describe(expected),
+                describe(actual)
+            ),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(mut checkouts: Vec<Checkout>) -> Self {
+        checkouts.sort_by(|a, b| a.identity.cmp(&b.identity));
+        Self {
+            schema_version: SCHEMA_VERSION,
+            checkouts,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, PackageRepoError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Writes the manifest atomically so a reader never sees a partial file.
+    pub fn write(&self, path: &Path) -> Result<(), PackageRepoError> {
+        write_atomic(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Lists how `current` differs from `self`.
+    pub fn diff(&self, current: &Manifest) -> Vec<Drift> {
+        let expected: BTreeMap<&str, &Checkout> = self
+            .checkouts
+            .iter()
+            .map(|checkout| (checkout.identity.as_str(), checkout))
+            .collect();
+        let actual: BTreeMap<&str, &Checkout> = current
+            .checkouts
+            .iter()
+            .map(|checkout| (checkout.identity.as_str(), checkout))
+            .collect();
+
+        let mut drift = Vec::new();
+        for (identity, expected) in &expected {
+            let Some(actual) = actual.get(identity) else {
+                drift.push(Drift::Removed(identity.to_string()));
+                continue;
+            };
+            if expected.head != actual.head {
+                drift.push(Drift::HeadChanged {
+                    identity: identity.to_string(),
+                    expected: expected.head.clone(),
+                    actual: actual.head.clone(),
+                });
+            }
+            if expected.proxy != actual.proxy {
+                drift.push(Drift::ProxyChanged {
+                    identity: identity.to_string(),
+                    expected: expected.proxy.clone(),
+                    actual: actual.proxy.clone(),
+                });
+            }
+        }
+        for identity in actual.keys() {
+            if !expected.contains_key(identity) {
+                drift.push(Drift::Added(identity.to_string()));
+            }
+        }
+        drift
+    }
+}
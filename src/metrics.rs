@@ -0,0 +1,97 @@
+use std::{fmt::Write, path::Path, time::Duration};
+
+use crate::{fs::write_atomic, output::Action, repo::PackageRepoError};
+
+/// Counts of what happened during an install, written in the Prometheus
+/// textfile format for node exporters to pick up.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    pins: usize,
+    cloned: usize,
+    fetched: usize,
+    linked: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl Metrics {
+    pub fn record(&mut self, action: Action) {
+        self.pins += 1;
+        match action {
+            Action::Cloned => self.cloned += 1,
+            Action::Fetched => self.fetched += 1,
+            Action::Linked => self.linked += 1,
+            Action::Skipped => self.skipped += 1,
+            Action::Failed => self.failed += 1,
+        }
+    }
+
+    pub fn render(&self, duration: Duration, succeeded: bool) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP spm_git_swap_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE spm_git_swap_{} {}", name, kind);
+            let _ = writeln!(out, "spm_git_swap_{} {}", name, value);
+        };
+        let counts = [
+            (
+                "pins",
+                "Packages processed by the last install.",
+                self.pins,
+            ),
+            (
+                "cloned",
+                "Packages cloned by the last install.",
+                self.cloned,
+            ),
+            (
+                "fetched",
+                "Checkouts fetched by the last install.",
+                self.fetched,
+            ),
+            (
+                "linked",
+                "Existing checkouts linked without fetching.",
+                self.linked,
+            ),
+            (
+                "skipped",
+                "Packages skipped by the last install.",
+                self.skipped,
+            ),
+            (
+                "failed",
+                "Packages that failed in the last install.",
+                self.failed,
+            ),
+        ];
+        // Every install overwrites the file, so the counts start from zero
+        // each run and are gauges rather than counters.
+        for (name, help, value) in counts {
+            metric(name, "gauge", help, value.to_string());
+        }
+        metric(
+            "duration_seconds",
+            "gauge",
+            "Wall-clock time of the last install.",
+            format!("{:.3}", duration.as_secs_f64()),
+        );
+        metric(
+            "success",
+            "gauge",
+            "Whether the last install succeeded.",
+            (succeeded as u8).to_string(),
+        );
+        out
+    }
+
+    /// Writes the metrics atomically so a scraper never reads a partial file.
+    pub fn write(
+        &self,
+        path: &Path,
+        duration: Duration,
+        succeeded: bool,
+    ) -> Result<(), PackageRepoError> {
+        write_atomic(path, self.render(duration, succeeded))
+    }
+}
@@ -0,0 +1,130 @@
+use std::{io::Write, str::FromStr};
+
+use serde::Serialize;
+
+use crate::resolved::v2;
+
+/// How `install`, `list` and `status` report their results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// One JSON object per package, written to stdout as soon as it is
+    /// known. Logging stays on stderr.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "json" | "jsonl" => Ok(OutputFormat::JsonLines),
+            _ => Err(format!("unknown format {}, expected text or json", value)),
+        }
+    }
+}
+
+/// What happened to a single package during an install.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Cloned,
+    Fetched,
+    /// An existing checkout was wired up without touching the network.
+    Linked,
+    Skipped,
+    Failed,
+}
+
+/// Whether the action on a package succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinEvent<'a> {
+    pub identity: &'a str,
+    pub location: &'a str,
+    pub action: Action,
+    pub revision: &'a str,
+    pub version: Option<&'a str>,
+    pub result: Outcome,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_output: Option<String>,
+}
+
+impl<'a> PinEvent<'a> {
+    pub fn new(pin: &'a v2::Pin, action: Action, error: Option<String>) -> Self {
+        Self {
+            identity: &pin.identity,
+            location: &pin.location,
+            action,
+            revision: &pin.state.revision,
+            version: pin.state.version.as_deref(),
+            result: match (action, &error) {
+                (Action::Failed, _) | (_, Some(_)) => Outcome::Error,
+                _ => Outcome::Ok,
+            },
+            error,
+            hook_output: None,
+        }
+    }
+
+    pub fn with_hook_output(mut self, hook_output: Option<String>) -> Self {
+        self.hook_output = hook_output;
+        self
+    }
+}
+
+/// A package pinned by the resolved files, as listed by `list`.
+#[derive(Debug, Serialize)]
+pub struct ListEvent<'a> {
+    pub identity: &'a str,
+    pub location: &'a str,
+    pub action: &'static str,
+    pub revision: &'a str,
+    pub version: Option<&'a str>,
+}
+
+impl<'a> ListEvent<'a> {
+    pub fn new(pin: &'a v2::Pin) -> Self {
+        Self {
+            identity: &pin.identity,
+            location: &pin.location,
+            action: "list",
+            revision: &pin.state.revision,
+            version: pin.state.version.as_deref(),
+        }
+    }
+}
+
+/// Whether a package's checkout matches its pin, as reported by `status`.
+/// `result` is `ok`, `wrong-revision`, `missing` or `not-swapped`.
+#[derive(Debug, Serialize)]
+pub struct StatusEvent<'a> {
+    pub identity: &'a str,
+    pub location: &'a str,
+    pub action: &'static str,
+    pub revision: &'a str,
+    pub result: &'static str,
+    /// The commit the checkout is at, when it differs from the pin.
+    pub head: Option<String>,
+}
+
+/// Writes an event to stdout as a single line and flushes it immediately.
+/// Holding the stdout lock for the whole line keeps events from concurrent
+/// installs from interleaving.
+pub fn emit(event: &impl Serialize) {
+    let Ok(line) = serde_json::to_string(event) else {
+        return;
+    };
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
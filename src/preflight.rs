@@ -0,0 +1,157 @@
+use std::{collections::BTreeMap, sync::mpsc, thread, time::Duration};
+
+use auth_git2::GitAuthenticator;
+use git2::{Direction, ErrorClass, ErrorCode, Oid, Remote, RemoteCallbacks};
+use log::{error, info, warn};
+
+use crate::{
+    git_config, repo,
+    resolved::v2,
+    url::{self, UrlRewriter},
+};
+
+/// Outcome of checking a remote before installing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostStatus {
+    Ok,
+    AuthFailed(String),
+    Unreachable(String),
+    TimedOut,
+    /// The remote is reachable but no ref points at the pinned revision.
+    RevisionNotFound,
+}
+
+impl HostStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, HostStatus::Ok)
+    }
+}
+
+/// Runs an `ls-remote` against one repository per distinct host and returns
+/// the result for each host so the install phase can skip hosts that failed.
+pub fn check_hosts(
+    git: &GitAuthenticator,
+    rewriter: &UrlRewriter,
+    http_proxy: Option<&str>,
+    pins: &[v2::Pin],
+    timeout: Duration,
+) -> BTreeMap<String, HostStatus> {
+    let mut samples: BTreeMap<String, String> = BTreeMap::new();
+    for pin in pins {
+        if pin.kind != v2::Kind::RemoteSourceControl {
+            continue;
+        }
+        let repo_url = rewriter.rewrite(pin);
+        if let Some(host) = url::host(&repo_url) {
+            samples.entry(host).or_insert(repo_url);
+        }
+    }
+
+    info!("Preflight: checking {} host(s)", samples.len());
+
+    let mut results = BTreeMap::new();
+    for (host, repo_url) in samples {
+        let status = check_url(git, &repo_url, http_proxy, timeout, None);
+        match &status {
+            HostStatus::Ok => info!("Preflight: {} OK", host),
+            HostStatus::AuthFailed(message) => {
+                error!("Preflight: authentication failed for {}: {}", host, message)
+            }
+            HostStatus::Unreachable(message) => {
+                error!("Preflight: {} is unreachable: {}", host, message)
+            }
+            HostStatus::TimedOut => warn!(
+                "Preflight: {} did not respond within {}s",
+                host,
+                timeout.as_secs()
+            ),
+            HostStatus::RevisionNotFound => {}
+        }
+        results.insert(host, status);
+    }
+
+    results
+}
+
+/// Runs an `ls-remote` against every git pin's clone URL and checks that a
+/// ref advertises the pinned revision. Nothing is written to disk.
+pub fn check_pins(
+    git: &GitAuthenticator,
+    rewriter: &UrlRewriter,
+    http_proxy: Option<&str>,
+    pins: &[v2::Pin],
+    timeout: Duration,
+) -> Vec<(v2::Pin, HostStatus)> {
+    pins.iter()
+        .filter(|pin| pin.kind == v2::Kind::RemoteSourceControl)
+        .map(|pin| {
+            let revision = Oid::from_str(&pin.state.revision).ok();
+            let status = check_url(git, &rewriter.rewrite(pin), http_proxy, timeout, revision);
+            (pin.clone(), status)
+        })
+        .collect()
+}
+
+/// Connects to `repo_url` and, when `revision` is given, checks that one of
+/// the advertised refs points at it.
+fn check_url(
+    git: &GitAuthenticator,
+    repo_url: &str,
+    http_proxy: Option<&str>,
+    timeout: Duration,
+    revision: Option<Oid>,
+) -> HostStatus {
+    let (sender, receiver) = mpsc::channel();
+    let git = git.clone();
+    let repo_url = repo_url.to_string();
+    let http_proxy = http_proxy.map(String::from);
+
+    // The thread is detached on timeout; libgit2 offers no way to cancel a
+    // connection that is stuck before any callbacks fire.
+    thread::spawn(move || {
+        let _ = sender.send(ls_remote(&git, &repo_url, http_proxy.as_deref()));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(advertised)) => match revision {
+            Some(revision) if !advertised.contains(&revision) => HostStatus::RevisionNotFound,
+            _ => HostStatus::Ok,
+        },
+        Ok(Err(err)) if is_auth_error(&err) => HostStatus::AuthFailed(err.message().to_string()),
+        Ok(Err(err)) => HostStatus::Unreachable(err.message().to_string()),
+        Err(_) => HostStatus::TimedOut,
+    }
+}
+
+/// Returns the oids every advertised ref points at, including peeled tags.
+fn ls_remote(
+    git: &GitAuthenticator,
+    repo_url: &str,
+    http_proxy: Option<&str>,
+) -> Result<Vec<Oid>, git2::Error> {
+    let config = git_config::open_default()?;
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(git.credentials(&config));
+
+    let mut remote = Remote::create_detached(repo_url)?;
+    let connection = remote.connect_auth(
+        Direction::Fetch,
+        Some(callbacks),
+        repo::proxy_options(http_proxy),
+    )?;
+    let advertised = connection
+        .list()?
+        .iter()
+        .map(|head| head.oid())
+        .collect();
+    Ok(advertised)
+}
+
+/// Whether `err` means the credentials were missing or rejected.
+pub fn is_auth_error(err: &git2::Error) -> bool {
+    err.code() == ErrorCode::Auth
+        || err.code() == ErrorCode::Certificate
+        || err.class() == ErrorClass::Ssh
+        || err.message().contains("401")
+        || err.message().contains("403")
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::IsTerminal,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::info;
+
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a transfer redraws the status line.
+const DRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+struct State {
+    done: usize,
+    failed: usize,
+    current: Option<String>,
+    last_log: Instant,
+}
+
+/// Aggregates per-package progress into a single status line.
+///
+/// On a terminal the line is redrawn in place. Otherwise it is logged at most
+/// every few seconds so CI logs stay readable.
+pub struct Progress {
+    total: usize,
+    bar: Option<ProgressBar>,
+    state: Mutex<State>,
+}
+
+impl Progress {
+    pub fn new(total: usize, verbose: bool) -> Self {
+        let bar = if std::io::stderr().is_terminal() && !verbose {
+            let bar = ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stderr());
+            bar.set_style(
+                ProgressStyle::with_template("[{pos}/{len}] {msg}")
+                    .expect("progress template is valid"),
+            );
+            bar.enable_steady_tick(Duration::from_millis(250));
+            Some(bar)
+        } else {
+            None
+        };
+
+        Self {
+            total,
+            bar,
+            state: Mutex::new(State {
+                done: 0,
+                failed: 0,
+                current: None,
+                last_log: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether the status line is being drawn on a terminal.
+    pub fn is_interactive(&self) -> bool {
+        self.bar.is_some()
+    }
+
+    /// The status line, for transfers to show how far they have got.
+    pub fn bar(&self) -> Option<ProgressBar> {
+        self.bar.clone()
+    }
+
+    pub fn start(&self, identity: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.current = Some(identity.to_string());
+        self.update(&mut state, false);
+    }
+
+    pub fn finish(&self, identity: &str, succeeded: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.done += 1;
+        if !succeeded {
+            state.failed += 1;
+        }
+        if state.current.as_deref() == Some(identity) {
+            state.current = None;
+        }
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+        self.update(&mut state, false);
+    }
+
+    pub fn complete(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.current = None;
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+        self.update(&mut state, true);
+    }
+
+    fn update(&self, state: &mut State, force: bool) {
+        let mut message = match &state.current {
+            Some(identity) => format!("cloning {}…", identity),
+            None if state.done == self.total => String::from("done"),
+            None => String::from("waiting…"),
+        };
+        if state.failed > 0 {
+            message.push_str(&format!(" ({} failed)", state.failed));
+        }
+
+        match &self.bar {
+            Some(bar) if !force => bar.set_message(message),
+            _ => {
+                if force || state.last_log.elapsed() >= LOG_INTERVAL {
+                    state.last_log = Instant::now();
+                    info!("[{}/{}] {}", state.done, self.total, message);
+                }
+            }
+        }
+    }
+}
+
+struct TransferState {
+    last_progress: Instant,
+    last_draw: Instant,
+}
+
+/// Tracks a single clone or fetch: when data last arrived, for `--timeout`,
+/// and how many objects and bytes were received, drawn on the status line
+/// when there is one. Shared with the thread running the transfer.
+#[derive(Clone)]
+pub struct TransferProgress {
+    identity: String,
+    bar: Option<ProgressBar>,
+    state: Arc<Mutex<TransferState>>,
+}
+
+impl TransferProgress {
+    pub fn new(identity: String, bar: Option<ProgressBar>) -> Self {
+        let now = Instant::now();
+        Self {
+            identity,
+            bar,
+            state: Arc::new(Mutex::new(TransferState {
+                last_progress: now,
+                // Draw the first update straight away.
+                last_draw: now.checked_sub(DRAW_INTERVAL).unwrap_or(now),
+            })),
+        }
+    }
+
+    /// Records that the remote sent something, such as its own progress
+    /// messages while it counts objects.
+    pub fn touch(&self) {
+        self.state.lock().unwrap().last_progress = Instant::now();
+    }
+
+    /// Records received objects, redrawing the status line at most every
+    /// 250ms.
+    pub fn update(&self, stats: &git2::Progress) {
+        let mut state = self.state.lock().unwrap();
+        state.last_progress = Instant::now();
+        let Some(bar) = &self.bar else {
+            return;
+        };
+        if state.last_draw.elapsed() < DRAW_INTERVAL {
+            return;
+        }
+        state.last_draw = Instant::now();
+        bar.set_message(format!(
+            "cloning {}… {}/{} objects, {}",
+            self.identity,
+            stats.received_objects(),
+            stats.total_objects(),
+            format_bytes(stats.received_bytes() as u64)
+        ));
+    }
+
+    /// How long it has been since the remote last sent anything.
+    pub fn idle(&self) -> Duration {
+        self.state.lock().unwrap().last_progress.elapsed()
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -1,12 +1,40 @@
-use std::{path, process::Command};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use auth_git2::GitAuthenticator;
-use git2::Config;
 use log::{info, warn};
 
 use thiserror::Error;
 
-use crate::resolved::{parse_all_recursive, v2};
+use crate::{
+    binary,
+    bundle::{self, Staging},
+    cas,
+    checkout::{self, CheckoutBy},
+    diagnostics::{self, Diagnostic, DiagnosticsFormat, Severity},
+    git_config::{self, ConfigTransaction, KeyStyle},
+    lock,
+    manifest::{self, Manifest},
+    metrics::Metrics,
+    output::{self, Action, OutputFormat, PinEvent, StatusEvent},
+    preflight,
+    progress::{format_bytes, Progress, TransferProgress},
+    resolved::{self, parse_all_recursive, v2, DedupeStrategy},
+    script::Script,
+    settings::{Settings, SETTINGS_FILE},
+    signature::{self, SignatureStatus},
+    summary::Summary,
+    token::Token,
+    url::{self, UrlRewriter},
+};
 
 #[derive(Error, Debug)]
 pub enum PackageRepoError {
@@ -24,24 +52,514 @@ pub enum PackageRepoError {
 
     #[error("Git config error: {0}")]
     GitConfig(String),
+
+    #[error("Cannot relocate: {0}")]
+    Relocate(String),
+
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+
+    #[error("Found {0} difference(s) from the manifest")]
+    Drift(usize),
+
+    #[error("{0} already exists and is not a git repository, use --force to replace it")]
+    NotARepository(path::PathBuf),
+
+    #[error("No pins found under {0}, refusing to treat every checkout as unused")]
+    NoPins(path::PathBuf),
+
+    #[error("Url map error: {0}")]
+    UrlMap(String),
+
+    #[error("Disk full while cloning {0}")]
+    DiskFull(String),
+
+    #[error("Only {available} bytes free in the checkouts directory, at least {required} are required")]
+    LowDiskSpace { available: u64, required: u64 },
+
+    #[error("Revision {revision} of {identity} was not found on any branch or tag of its remote")]
+    RevisionNotFound { identity: String, revision: String },
+
+    #[error("Branch {branch} of {identity} was not found on its remote")]
+    BranchNotFound { identity: String, branch: String },
+
+    #[error("No checkout at {0} and --offline was given")]
+    MissingCheckout(path::PathBuf),
+
+    #[error("Failed to remove {0} checkout(s)")]
+    WipeIncomplete(usize),
+
+    #[error("No checkout of {0} was found")]
+    NoCheckout(String),
+
+    #[error("{} is locked by another run{}. Wait for it to finish or pass --no-lock", .path.display(), .pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default())]
+    Locked { path: path::PathBuf, pid: Option<u32> },
+
+    #[error("Not confirmed: {0}")]
+    NotConfirmed(String),
+
+    #[error("Settings error: {0}")]
+    Settings(String),
+
+    #[error("{0} pinned revision(s) are not signed by a trusted key")]
+    Unverified(usize),
+
+    #[error("{0} package(s) use an insecure http:// URL")]
+    InsecureUrl(usize),
+
+    #[error("Checkout hook failed ({status}): {output}")]
+    HookFailed { status: String, output: String },
+
+    #[error("Missing libgit2 feature: {0}")]
+    MissingFeature(String),
+
+    #[error("Download error: {0}")]
+    Download(String),
+
+    #[error("Checksum mismatch for {identity}: expected {expected}, downloaded {actual}")]
+    ChecksumMismatch {
+        identity: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Bundle error: {0}")]
+    Bundle(String),
+
+    #[error("{0} package(s) are out of sync with the resolved files")]
+    OutOfSync(usize),
+
+    #[error("{0} git config entry(ies) point at a broken checkout")]
+    BrokenProxies(usize),
+
+    #[error("{0} package(s) failed the check")]
+    CheckFailed(usize),
+
+    #[error("{0} package(s) failed to install")]
+    InstallFailed(usize),
+
+    #[error("Deadline exceeded: {completed} package(s) completed, {skipped} skipped")]
+    DeadlineExceeded { completed: usize, skipped: usize },
+
+    #[error("Timed out after {}s without progress", .0.as_secs())]
+    TimedOut(Duration),
+}
+
+impl PackageRepoError {
+    /// The process exit code to use when a command fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PackageRepoError::DiskFull(_) | PackageRepoError::LowDiskSpace { .. } => {
+                EXIT_DISK_FULL
+            }
+            PackageRepoError::DeadlineExceeded { .. } => EXIT_DEADLINE_EXCEEDED,
+            _ => 1,
+        }
+    }
+
+    fn is_disk_full(&self) -> bool {
+        match self {
+            PackageRepoError::DiskFull(_) => true,
+            PackageRepoError::Io(err) => is_disk_full_os_error(err.raw_os_error()),
+            PackageRepoError::Git(err) => {
+                let message = err.message().to_lowercase();
+                message.contains("no space left on device")
+                    || message.contains("not enough space on the disk")
+                    || message.contains("disk quota exceeded")
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether trying again might get past the error: network and transport
+    /// failures, but not rejected credentials or a repository that doesn't
+    /// exist.
+    fn is_transient(&self) -> bool {
+        let PackageRepoError::Git(err) = self else {
+            return false;
+        };
+        let message = err.message().to_lowercase();
+        let not_found = message.contains("not found")
+            || message.contains("does not exist")
+            || message.contains("not exported")
+            || (message.contains("status code: 4") && !message.contains("429"));
+        // Refused connections and failed lookups come from the socket layer.
+        let network = match err.class() {
+            git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssl => true,
+            git2::ErrorClass::Os => {
+                message.contains("failed to connect") || message.contains("failed to resolve address")
+            }
+            _ => false,
+        };
+        network
+            && !preflight::is_auth_error(err)
+            && !not_found
+            && !self.is_disk_full()
+    }
+}
+
+fn is_disk_full_os_error(code: Option<i32>) -> bool {
+    #[cfg(unix)]
+    const CODES: &[i32] = &[28, 122]; // ENOSPC, EDQUOT
+    #[cfg(windows)]
+    const CODES: &[i32] = &[39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+
+    code.is_some_and(|code| CODES.contains(&code))
 }
 
 const CHECKOUTS_DIR: &str = "checkouts";
+/// Locked by a running install or wipe, in the repo directory.
+const RUN_LOCK_FILE: &str = "run.lock";
+const BINARIES_DIR: &str = "binaries";
+const EXIT_DISK_FULL: i32 = 3;
+const EXIT_DEADLINE_EXCEEDED: i32 = 4;
+const DEFAULT_REMOTE: &str = "origin";
+/// Longest checkout path used as is on Windows. Leaves room below MAX_PATH
+/// (260) for the files inside the checkout.
+const MAX_WINDOWS_CHECKOUT_PATH: usize = 160;
+const DEFAULT_PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Marker inside a checkout's git directory, touched after every successful
+/// clone or fetch.
+const FETCHED_MARKER: &str = "spm-git-swap-fetched";
+/// Where the branches and tags of a host mirror are advertised while a clone
+/// borrows its objects.
+const MIRROR_REFS: &str = "refs/spm-git-swap-mirror/";
+/// Fetches the remote's branches into a bare clone's own branches.
+const MIRROR_REFSPEC: &str = "+refs/heads/*:refs/heads/*";
+
+/// What `install` does with packages pinned to a plain `http://` URL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InsecurePolicy {
+    /// Fail before installing anything.
+    #[default]
+    Reject,
+    /// Leave the package out with a warning.
+    Skip,
+}
+
+impl std::str::FromStr for InsecurePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(InsecurePolicy::Reject),
+            "skip" => Ok(InsecurePolicy::Skip),
+            _ => Err(format!("unknown policy {}, expected reject or skip", value)),
+        }
+    }
+}
+
+/// How a checkout on disk compares to the pin it was installed for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    Ok,
+    /// HEAD is at a different commit, or nothing, instead of the pinned
+    /// revision.
+    WrongRevision(Option<String>),
+    /// There is no git repository at the checkout path.
+    Missing,
+    /// The checkout is right but the git config doesn't redirect the pin's
+    /// location to it.
+    NotSwapped,
+}
+
+impl SyncStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncStatus::Ok => "ok",
+            SyncStatus::WrongRevision(_) => "wrong-revision",
+            SyncStatus::Missing => "missing",
+            SyncStatus::NotSwapped => "not-swapped",
+        }
+    }
+}
+
+impl std::fmt::Display for SyncStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncStatus::WrongRevision(Some(head)) => write!(f, "{} (at {})", self.name(), head),
+            SyncStatus::WrongRevision(None) => write!(f, "{} (no HEAD)", self.name()),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// How a proxy entry in the git config compares to the checkout it points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHealth {
+    Ok,
+    /// Nothing exists at the path.
+    Missing,
+    /// The path exists but can't be opened as a git repository.
+    NotARepository,
+    /// The repository has no HEAD, or it doesn't point at a commit.
+    NoHead,
+    /// The checkout's remote is for a different package than the redirected
+    /// url.
+    Mismatch,
+}
+
+impl ProxyHealth {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProxyHealth::Ok => "ok",
+            ProxyHealth::Missing => "missing",
+            ProxyHealth::NotARepository => "not-a-repository",
+            ProxyHealth::NoHead => "no-head",
+            ProxyHealth::Mismatch => "mismatch",
+        }
+    }
+
+    /// Whether the entry points at no checkout at all, so removing it loses
+    /// nothing.
+    pub fn is_dangling(&self) -> bool {
+        matches!(self, ProxyHealth::Missing | ProxyHealth::NotARepository)
+    }
+}
+
+/// Options that control a single `install` run.
+#[derive(Debug, Default, Clone)]
+pub struct InstallOptions {
+    /// Apply git config changes as soon as each package is processed instead
+    /// of staging them and applying them together at the end of the run.
+    pub no_transaction: bool,
+
+    /// Check that every host is reachable with the available credentials
+    /// before cloning anything.
+    pub preflight: bool,
+
+    /// Upper bound for network operations. A clone or fetch whose remote
+    /// sends nothing for this long is given up on and its package counted as
+    /// failed.
+    pub timeout: Option<Duration>,
+
+    /// Log every package instead of showing a single status line.
+    pub verbose: bool,
+
+    /// Git wire protocol version to request. Only honoured by the http(s)
+    /// transports, which send it as a `Git-Protocol` header.
+    pub git_protocol: Option<u8>,
+
+    /// Print the git config fragment to stdout instead of writing it to the
+    /// git config.
+    pub print_config: bool,
+
+    /// Replace directories in the way of a checkout that aren't a git
+    /// repository or a clone that died, and `insteadOf` values for a checkout
+    /// path that spm-git-swap didn't write instead of adding ours next to
+    /// them.
+    pub force: bool,
+
+    /// How the outcome of each package is reported.
+    pub format: OutputFormat,
+
+    /// Update the modification time of reused checkouts so external cache
+    /// eviction sees them as recently used.
+    pub touch: bool,
+
+    /// Chooses the URL each package is cloned from.
+    pub url_rewriter: UrlRewriter,
+
+    /// Which git config keys redirect the original URL to the checkout.
+    pub key_style: KeyStyle,
+
+    /// Never touch the network. Checkouts must already exist, for example
+    /// because they were fetched by an external downloader, and only the git
+    /// config is updated.
+    pub offline: bool,
+
+    /// Only fetch and check out packages that already have a checkout,
+    /// skipping the rest instead of cloning them. The resolved files win over
+    /// the lockfile, as with `update_lock`.
+    pub existing_only: bool,
+
+    /// A SwiftPM cache directory whose checkouts are reused when they already
+    /// contain the pinned revision.
+    pub reuse_spm_cache: Option<path::PathBuf>,
+
+    /// Minimum number of free bytes required on the filesystem holding the
+    /// checkouts before each clone or fetch.
+    pub min_free_space: Option<u64>,
+
+    /// Regenerate the lockfile from the resolved files instead of preferring
+    /// the revisions it records.
+    pub update_lock: bool,
+
+    /// Proxy for http(s) remotes. `auto` uses git's own `http.proxy` config
+    /// and the usual proxy environment variables.
+    pub http_proxy: Option<String>,
+
+    /// Name of the remote created when cloning. Defaults to `origin`.
+    pub remote_name: Option<String>,
+
+    /// Upper bound for the whole run. Packages not reached in time are
+    /// skipped and a clone still running when it passes is cancelled.
+    pub deadline: Option<Duration>,
+
+    /// Where to write Prometheus textfile metrics once the run finishes.
+    pub metrics_file: Option<path::PathBuf>,
+
+    /// Where to write the end of run summary as JSON.
+    pub summary_json: Option<path::PathBuf>,
+
+    /// Point the remote of existing checkouts at the freshly rewritten URL
+    /// instead of fetching from the URL they were cloned from.
+    pub rewrite_existing_remotes: bool,
+
+    /// Also write the pins that changed since the previous lockfile to this
+    /// file.
+    pub changed_report: Option<path::PathBuf>,
+
+    /// Which ref HEAD of each checkout is moved to at the pinned revision,
+    /// when the pin has one.
+    pub checkout_by: CheckoutBy,
+
+    /// Only check that every pin's remote is reachable and advertises the
+    /// pinned revision, without cloning or touching the git config.
+    pub check: bool,
+
+    /// Refuse, or skip, packages pinned to a plain `http://` URL.
+    pub require_https: Option<InsecurePolicy>,
+
+    /// Shell command run after each package is checked out. A non-zero exit
+    /// marks the package as failed.
+    pub checkout_hook: Option<String>,
+
+    /// Only scan the resolved files that changed relative to this git ref.
+    pub changed_files_from: Option<String>,
+
+    /// How problems found in the resolved files are reported.
+    pub diagnostics_format: DiagnosticsFormat,
+
+    /// Which pin wins when resolved files pin the same package differently.
+    pub dedupe_strategy: DedupeStrategy,
+
+    /// Existing checkouts fetched more recently than this, and already
+    /// holding the pinned revision, are linked without fetching. Older ones
+    /// are fetched and checked out at the pinned revision.
+    pub refresh_older_than: Option<Duration>,
+
+    /// Fetch existing checkouts even when they already hold the pinned
+    /// revision.
+    pub force_fetch: bool,
+
+    /// Write the git commands the run would execute to this shell script
+    /// instead of running them.
+    pub emit_script: Option<path::PathBuf>,
+
+    /// Number of threads reading and parsing resolved files.
+    pub scan_jobs: usize,
+
+    /// Bare mirrors, by host, that fresh clones of packages on that host
+    /// borrow objects from, like `git clone --reference`.
+    pub host_mirrors: BTreeMap<String, path::PathBuf>,
+
+    /// Number of packages cloned or fetched at the same time.
+    pub jobs: usize,
+
+    /// Print what the install would do without cloning, fetching or
+    /// touching the git config or lockfile.
+    pub dry_run: bool,
+
+    /// Only install the packages whose identity matches one of these
+    /// patterns. Every package is installed when empty.
+    pub filters: Vec<glob::Pattern>,
+
+    /// Leave out the packages whose identity matches one of these patterns,
+    /// after applying the filters.
+    pub excludes: Vec<glob::Pattern>,
+
+    /// Clone with only this many commits of history from each branch tip.
+    /// Existing shallow checkouts are fetched to the same depth.
+    pub depth: Option<u32>,
+
+    /// Store checkouts as bare repositories mirroring the remote's branches.
+    /// SwiftPM only ever clones from them, so the work tree is dead weight.
+    pub bare: bool,
+
+    /// How many times a clone or fetch is retried after an error that looks
+    /// transient.
+    pub retries: u32,
+
+    /// Succeed even when some packages failed, as long as nothing aborted
+    /// the install.
+    pub keep_going: bool,
+
+    /// Stop starting packages once one has failed, including by its checkout
+    /// hook. Packages already being installed still finish.
+    pub fail_fast: bool,
+}
+
+/// How installing a single package ended.
+enum PinOutcome {
+    /// Cloned, fetched or linked.
+    Completed,
+    /// Failed on its own, or its host failed preflight. The other packages
+    /// carry on.
+    Failed,
+    /// A clone or fetch stalled for longer than `--timeout` and was given
+    /// up on. Counted as failed.
+    TimedOut(String),
+    /// Not attempted, or cancelled, because the deadline passed.
+    Skipped,
+    /// Not attempted because the install was aborted.
+    Unreached,
+    /// Failed in a way that stops the whole install.
+    Aborted(PackageRepoError),
+}
 
 pub struct PackageRepo {
     dir: path::PathBuf,
-    git: GitAuthenticator,
+    /// Not `Sync`, so every network operation works on its own copy.
+    git: Mutex<GitAuthenticator>,
+    /// Shared by the threads installing packages in parallel, so config
+    /// changes are staged or written one at a time.
+    config: Mutex<ConfigTransaction>,
+    options: InstallOptions,
+    deadline: Option<Instant>,
+    metrics: Mutex<Metrics>,
+    summary: Mutex<Summary>,
+    /// The status line of the running install, which transfers draw their
+    /// progress on.
+    status_line: Option<indicatif::ProgressBar>,
+    /// A content-addressed store of bare clones, shared with other tools,
+    /// used instead of the checkouts directory.
+    cas_dir: Option<path::PathBuf>,
+    /// A git repository whose own config holds the proxy entries instead of
+    /// the global config.
+    local_config: Option<path::PathBuf>,
+    /// Holds the exclusive lock on the repo directory while this is alive.
+    run_lock: Option<std::fs::File>,
 }
 
 impl PackageRepo {
-    pub fn new() -> Result<Self, PackageRepoError> {
-        let working_dir = std::env::current_dir()?;
-        let repo_dir = std::env::var("REPO_DIR").unwrap_or_else(|_| {
-            warn!("REPO_DIR not set, using current directory({}/swifter-package-manager/checkouts) to store packages", working_dir.display());
-            working_dir.join(path::Path::new("swifter-package-manager")).display().to_string()
-        });
-        let repo_dir = path::Path::new(&repo_dir);
+    /// Creates the repo and checkouts directories and a starter settings
+    /// file. Safe to run more than once.
+    pub fn init(repo_dir: &path::Path) -> Result<(), PackageRepoError> {
+        std::fs::create_dir_all(repo_dir.join(CHECKOUTS_DIR))?;
+        let repo_dir = repo_dir.canonicalize()?;
+
+        if Settings::write_starter(&repo_dir)? {
+            info!(
+                "Wrote starter settings to {}",
+                repo_dir.join(SETTINGS_FILE).display()
+            );
+        } else {
+            info!(
+                "Settings already exist at {}",
+                repo_dir.join(SETTINGS_FILE).display()
+            );
+        }
+
+        println!("Repo directory is ready at {}", repo_dir.display());
+        println!("Add this to your shell profile to use it:");
+        println!("  export REPO_DIR=\"{}\"", repo_dir.display());
+        Ok(())
+    }
 
+    pub fn new(repo_dir: &path::Path) -> Result<Self, PackageRepoError> {
         if !repo_dir.exists() {
             info!("Creating repo directory at {}", repo_dir.display());
             std::fs::create_dir_all(repo_dir)?;
@@ -58,146 +576,3881 @@ impl PackageRepo {
 
         Ok(Self {
             dir: repo_dir.to_path_buf(),
-            git: GitAuthenticator::default()
-                .try_cred_helper(true)
-                .add_default_username()
-                .try_ssh_agent(true)
-                .add_default_ssh_keys(),
+            git: Mutex::new(authenticator(None)),
+            config: Mutex::new(ConfigTransaction::new(true)),
+            options: InstallOptions::default(),
+            deadline: None,
+            metrics: Mutex::new(Metrics::default()),
+            summary: Mutex::new(Summary::default()),
+            status_line: None,
+            cas_dir: None,
+            local_config: None,
+            run_lock: None,
         })
     }
 
-    pub fn wipe(&self) -> Result<(), PackageRepoError> {
-        info!(
-            "Wiping checkouts directory: {}",
-            self.checkouts_dir().display()
-        );
-        std::fs::remove_dir_all(self.checkouts_dir())?;
-        Ok(())
+    /// Authenticates ssh remotes with only this private key, such as a CI
+    /// deploy key, instead of the ssh agent and the default keys. Without
+    /// one, the key `GIT_SSH_COMMAND` passes with `-i` is used if it names
+    /// one.
+    pub fn with_ssh_key(mut self, ssh_key: Option<path::PathBuf>) -> Self {
+        let ssh_key = ssh_key.or_else(|| {
+            std::env::var("GIT_SSH_COMMAND")
+                .ok()
+                .and_then(|command| ssh_key_from_command(&command))
+        });
+        self.git = Mutex::new(authenticator(ssh_key));
+        self
     }
 
-    pub fn install(&mut self, path: &path::Path) -> Result<(), PackageRepoError> {
-        info!("Scanning directory: {:?} for Package.resovled", path);
-        let pins = parse_all_recursive(path)?;
+    /// Keeps bare clones in the content-addressed store at `cas_dir` instead
+    /// of the checkouts directory.
+    pub fn with_cas_dir(mut self, cas_dir: Option<path::PathBuf>) -> Self {
+        self.cas_dir = cas_dir;
+        self
+    }
 
-        for pin in pins {
-            info!("Cloning: {:?}", pin.identity);
-            if let Err(error) = self.clone(&pin) {
-                log::error!(
-                    "Error cloning {} at: {}. {}",
-                    pin.identity,
-                    pin.location,
-                    error,
-                );
+    /// The content-addressed store used instead of the checkouts directory.
+    pub fn cas_dir(&self) -> Option<&path::Path> {
+        self.cas_dir.as_deref()
+    }
+
+    /// Reads and writes the proxy entries in the config of the git
+    /// repository at `local_config` instead of the global config, so they
+    /// only apply to that project.
+    pub fn with_local_config(mut self, local_config: Option<path::PathBuf>) -> Self {
+        self.config = Mutex::new(ConfigTransaction::new(true).with_local(local_config.clone()));
+        self.local_config = local_config;
+        self
+    }
+
+    /// Takes an exclusive lock on the repo directory, held until this is
+    /// dropped, so two runs don't clone into the same checkouts or
+    /// interleave their git config changes. Fails straight away when
+    /// another process holds it. The OS releases the lock if the process
+    /// dies.
+    pub fn locked(mut self) -> Result<Self, PackageRepoError> {
+        let path = self.dir.join(RUN_LOCK_FILE);
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if fs2::FileExt::try_lock_exclusive(&file).is_err() {
+            // The holder writes its pid once it has the lock.
+            let pid = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok());
+            return Err(PackageRepoError::Locked { path, pid });
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        self.run_lock = Some(file);
+        Ok(self)
+    }
+
+    /// The config holding the proxy entries.
+    fn proxy_config(&self) -> Result<git2::Config, git2::Error> {
+        git_config::open(self.local_config.as_deref())
+    }
+
+    /// Removes every checkout. Each one is removed individually so a single
+    /// locked or permission-denied checkout doesn't stop the others from being
+    /// cleaned up.
+    pub fn wipe(&self) -> Result<(), PackageRepoError> {
+        let checkouts_dir = self.checkouts_dir();
+        info!("Wiping checkouts directory: {}", checkouts_dir.display());
+
+        let config = self.proxy_config()?;
+        let cas_checkouts = self.cas_checkouts(&config)?;
+        let mut proxied: Vec<path::PathBuf> =
+            git_config::proxy_entries_under(&config, &checkouts_dir)?
+                .into_iter()
+                .map(|entry| entry.path)
+                .chain(cas_checkouts.iter().cloned())
+                .collect();
+        proxied.sort();
+        proxied.dedup();
+
+        let mut failed = Vec::new();
+        for path in cas_checkouts {
+            info!("Removing {} from the content-addressed store", path.display());
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                log::error!("Failed to remove {}: {}", path.display(), err);
+                failed.push(path);
             }
         }
 
+        if checkouts_dir.exists() {
+            for entry in std::fs::read_dir(&checkouts_dir)? {
+                let path = entry?.path();
+                let result = if path.is_dir() {
+                    std::fs::remove_dir_all(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                };
+                if let Err(err) = result {
+                    log::error!("Failed to remove {}: {}", path.display(), err);
+                    failed.push(path);
+                }
+            }
+        }
+
+        // Checkouts that couldn't be removed keep their entries.
+        for path in proxied.iter().filter(|path| !path.exists()) {
+            info!("Removing git config entries for {}", path.display());
+            self.remove_global_git_proxy(&path.display().to_string())?;
+        }
+
+        if !failed.is_empty() {
+            return Err(PackageRepoError::WipeIncomplete(failed.len()));
+        }
+
+        if checkouts_dir.exists() {
+            std::fs::remove_dir(&checkouts_dir)?;
+        }
+        let installed = self.dir.join(manifest::INSTALLED_FILE);
+        if installed.exists() {
+            std::fs::remove_file(&installed)?;
+        }
+        let binaries_dir = self.dir.join(BINARIES_DIR);
+        if binaries_dir.exists() {
+            info!("Removing binary targets: {}", binaries_dir.display());
+            std::fs::remove_dir_all(&binaries_dir)?;
+        }
         Ok(())
     }
-}
 
-impl PackageRepo {
-    fn clone(&mut self, pin: &v2::Pin) -> Result<(), PackageRepoError> {
-        if pin.kind != v2::Kind::RemoteSourceControl {
-            info!("Skipping {} as it is not a git repo", pin.identity);
-            return Ok(());
+    /// Removes the checkout of a single package and the git config entries
+    /// pointing at it, leaving every other checkout alone. Store entries are
+    /// found by the identity of the url redirected to them.
+    pub fn wipe_package(&self, identity: &str) -> Result<(), PackageRepoError> {
+        let config = self.proxy_config()?;
+        let checkouts_dir = self.checkouts_dir();
+        let mut paths = Vec::new();
+        if checkouts_dir.exists() {
+            for entry in std::fs::read_dir(&checkouts_dir)? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().eq_ignore_ascii_case(identity) {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        for path in self.cas_checkouts(&config)? {
+            let matches = git_config::proxy_entries_under(&config, &path)?
+                .iter()
+                .any(|entry| url::identity(&entry.url) == identity.to_lowercase());
+            if matches {
+                paths.push(path);
+            }
         }
 
-        let mut repo_url = pin.location.clone();
+        if paths.is_empty() {
+            return Err(PackageRepoError::NoCheckout(identity.to_string()));
+        }
 
-        if pin.location.starts_with("https://github.com/") {
-            let parts: Vec<&str> = pin.location.split('/').collect();
-            let repo_name = parts[parts.len() - 1];
-            let user_name = parts[parts.len() - 2];
-            repo_url = format!("git@github.com:{}/{}", user_name, repo_name);
-            info!(
-                "Converting https to ssh for {}. Converted to {}",
-                pin.location, repo_url
-            );
+        for path in paths {
+            info!("Removing {}", path.display());
+            std::fs::remove_dir_all(&path)?;
+            info!("Removing git config entries for {}", path.display());
+            self.remove_global_git_proxy(&path.display().to_string())?;
         }
+        Ok(())
+    }
 
-        let version = pin
-            .state
-            .version
-            .clone()
-            .unwrap_or_else(|| String::from("NO_VERSION"));
+    /// Moves the repo directory `old`, with its checkouts, binary targets,
+    /// settings and manifest, to `new` and points every proxy entry in the
+    /// global config, or the config of the `local` repository, at the new
+    /// location.
+    pub fn relocate(
+        old: &path::Path,
+        new: &path::Path,
+        local: Option<&path::Path>,
+    ) -> Result<(), PackageRepoError> {
+        if !old.is_dir() {
+            return Err(PackageRepoError::Relocate(format!(
+                "{} does not exist",
+                old.display()
+            )));
+        }
 
-        let path = self.checkouts_dir().join(pin.identity.clone());
-        let git_path = path.join(".git");
+        let old_canonical = old.canonicalize()?;
+        if new.exists() {
+            if new.canonicalize()? == old_canonical {
+                return Err(PackageRepoError::Relocate(
+                    "source and destination are the same directory".to_string(),
+                ));
+            }
+            if std::fs::read_dir(new)?.next().is_some() {
+                return Err(PackageRepoError::Relocate(format!(
+                    "{} already exists and is not empty",
+                    new.display()
+                )));
+            }
+            std::fs::remove_dir(new)?;
+        }
 
-      
+        let parent = new
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(path::Path::new("."));
+        std::fs::create_dir_all(parent)?;
+        if parent.canonicalize()?.starts_with(&old_canonical) {
+            return Err(PackageRepoError::Relocate(format!(
+                "{} is inside {}",
+                new.display(),
+                old.display()
+            )));
+        }
 
-        Self::remove_global_git_proxy(&path.display().to_string())?;
+        info!("Moving {} to {}", old.display(), new.display());
+        move_dir(old, new)?;
 
-        if path.exists() && git_path.exists() {
-            info!("{} already exists, fetching", pin.identity);
+        // Entries may have been written with either spelling of the old path.
+        let new_checkouts = new.join(CHECKOUTS_DIR);
+        let mut old_checkouts = vec![old.join(CHECKOUTS_DIR)];
+        if old_canonical != old {
+            old_checkouts.push(old_canonical.join(CHECKOUTS_DIR));
+        }
+        let relocated = |path: &path::Path| {
+            old_checkouts
+                .iter()
+                .find_map(|old| path.strip_prefix(old).ok())
+                .map(|relative| new_checkouts.join(relative))
+        };
 
-            let repo = git2::Repository::open(&path)?;
-            let mut remote = repo.find_remote("origin")?;
+        let config = git_config::open(local)?;
+        let mut transaction =
+            ConfigTransaction::new(false).with_local(local.map(path::Path::to_path_buf));
+        let mut moved = HashSet::new();
+        for old in &old_checkouts {
+            for entry in git_config::proxy_entries_under(&config, old)? {
+                let new_path = relocated(&entry.path).expect("entry was filtered by prefix");
+                info!(
+                    "Rewriting proxy for {} to {}",
+                    entry.url,
+                    new_path.display()
+                );
+                let new_path = new_path.display().to_string();
+                transaction.remove_value(&entry.key, &entry.url)?;
+                transaction.add(
+                    &git_config::proxy_key_with(&new_path, entry.variable),
+                    &entry.url,
+                )?;
+                moved.insert((entry.path.display().to_string(), new_path));
+            }
+        }
+        // The marks of the values we wrote move along with them.
+        for (old_path, new_path) in moved {
+            let key = git_config::managed_key(&old_path);
+            for value in git_config::values(&config, &key) {
+                transaction.remove_value(&key, &value)?;
+                transaction.add(&git_config::managed_key(&new_path), &value)?;
+            }
+        }
+        transaction.commit()?;
 
-            self.git
-                .fetch(&repo, &mut remote, &["refs/heads/*:refs/heads/*"], None)?;
+        let installed = new.join(manifest::INSTALLED_FILE);
+        if let Ok(mut manifest) = Manifest::read(&installed) {
+            for checkout in &mut manifest.checkouts {
+                if let Some(path) = relocated(&checkout.path) {
+                    checkout.path = path;
+                }
+            }
+            manifest.write(&installed)?;
+        }
+        Ok(())
+    }
 
-            Self::set_global_git_proxy(&pin.location, &path.display().to_string())?;
+    /// Removes every proxy entry pointing into the checkouts directory or at
+    /// our part of the content-addressed store, leaving the repositories on
+    /// disk so `swap` can point the git config at them again.
+    pub fn unswap(&self) -> Result<(), PackageRepoError> {
+        let config = self.proxy_config()?;
+        let mut entries = git_config::proxy_entries_under(&config, &self.checkouts_dir())?;
+        if let Some(cas_dir) = &self.cas_dir {
+            entries.extend(git_config::proxy_entries_under(&config, cas_dir)?);
+        }
 
-            return Ok(());
-        } else {
-            info!("Cloning {} at {}", pin.identity, pin.location);
+        for entry in &entries {
+            info!("Removing proxy for {} to {}", entry.url, entry.path.display());
+            self.config.lock().unwrap().remove(&entry.key)?;
         }
+        info!("Removed {} proxy entry(s)", entries.len());
+        self.config.lock().unwrap().commit()
+    }
 
-        self.git.clone_repo(&repo_url, &path).or_else(|err| {
-            if path.exists() {
-                info!("Removing {} due to error cloning", path.display());
-                if let Err(deleter_error) = std::fs::remove_dir_all(&path) {
-                    log::error!(
-                        "Error deleting {} after error cloning: {}. You may need to manually delete this directory.",
-                        path.display(),
-                        deleter_error
-                    );
+    /// Points the git config at every repository in the checkouts directory
+    /// again, redirecting the url of its origin remote to it.
+    pub fn swap(&self) -> Result<(), PackageRepoError> {
+        let mut swapped = 0;
+        for entry in std::fs::read_dir(self.checkouts_dir())? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            let location = git2::Repository::open(&path)
+                .ok()
+                .as_ref()
+                .and_then(remote_url);
+            match location {
+                Some(location) => {
+                    info!("Redirecting {} to {}", location, path.display());
+                    self.set_global_git_proxy(&location, &path.display().to_string())?;
+                    swapped += 1;
                 }
+                None => warn!(
+                    "{} is not a git repository with a remote, leaving it alone",
+                    path.display()
+                ),
             }
-            Err(err)
-        })?;
+        }
+        info!("Swapped {} checkout(s)", swapped);
+        self.config.lock().unwrap().commit()
+    }
 
-        info!(
-            "Cloned {} , version {} at revision: {}",
-            pin.identity, version, pin.state.revision
-        );
+    /// Captures the checkouts currently on disk and the proxies pointing at
+    /// them.
+    pub fn manifest(&self) -> Result<Manifest, PackageRepoError> {
+        let config = self.proxy_config()?;
+        let proxies = git_config::proxy_entries_under(&config, &self.checkouts_dir())?;
 
-        info!(
-            "Setting global git proxy for {} to {}",
-            pin.location,
-            &path.display()
-        );
-        Self::set_global_git_proxy(&pin.location, &path.display().to_string())?;
+        let mut checkouts = Vec::new();
+        for entry in std::fs::read_dir(self.checkouts_dir())? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
 
-        Ok(())
+            let identity = entry.file_name().to_string_lossy().to_string();
+            checkouts.push(describe_checkout(identity, entry.path(), &proxies));
+        }
+
+        // Only the store entries our git config points at belong to us, the
+        // rest are managed by other tools.
+        for path in self.cas_checkouts(&config)? {
+            let identity = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let proxies = git_config::proxy_entries_under(&config, &path)?;
+            checkouts.push(describe_checkout(identity, path, &proxies));
+        }
+
+        Ok(Manifest::new(checkouts))
     }
 
-    fn checkouts_dir(&self) -> path::PathBuf {
-        self.dir.join(path::Path::new(CHECKOUTS_DIR))
+    /// Every url the git config redirects into the checkouts directory or
+    /// the content-addressed store, with the checkout it points at.
+    pub fn swapped(&self) -> Result<Vec<manifest::Swapped>, PackageRepoError> {
+        let config = self.proxy_config()?;
+        let mut entries = git_config::proxy_entries_under(&config, &self.checkouts_dir())?;
+        if let Some(cas_dir) = &self.cas_dir {
+            entries.extend(git_config::proxy_entries_under(&config, cas_dir)?);
+        }
+
+        let mut swapped: Vec<manifest::Swapped> = entries
+            .into_iter()
+            .map(|entry| {
+                let head = git2::Repository::open(&entry.path).ok().and_then(|repo| {
+                    repo.head()
+                        .ok()
+                        .and_then(|head| head.target())
+                        .map(|oid| oid.to_string())
+                });
+                manifest::Swapped {
+                    stale: !entry.path.exists(),
+                    url: entry.url,
+                    path: entry.path,
+                    head,
+                }
+            })
+            .collect();
+        // `insteadOf` and `pushInsteadOf` entries redirect the same url.
+        swapped.sort_by(|a, b| (&a.url, &a.path).cmp(&(&b.url, &b.path)));
+        swapped.dedup();
+        Ok(swapped)
     }
 
-    fn set_global_git_proxy(repo_url: &str, proxy_path: &str) -> Result<(), PackageRepoError> {
+    /// The checkouts, in the checkouts directory or our part of the
+    /// content-addressed store, that no pin in the resolved files under
+    /// `path` uses any more.
+    pub fn orphans(&self, path: &path::Path) -> Result<Vec<manifest::Checkout>, PackageRepoError> {
+        // A scan of the wrong directory finds nothing rather than failing.
+        let pins = Self::installed_pins(path)?;
+        if pins.is_empty() {
+            return Err(PackageRepoError::NoPins(path.to_path_buf()));
+        }
+        let used: HashSet<path::PathBuf> = pins
+            .iter()
+            .filter(|pin| pin.kind == v2::Kind::RemoteSourceControl)
+            .map(|pin| self.checkout_path(pin))
+            .collect();
 
-        let config_value = format!("url.{}.insteadOf", proxy_path);
-        
-        let mut config =  Config::open_default()?;
+        let mut orphans = self.manifest()?.checkouts;
+        orphans.retain(|checkout| !used.contains(&checkout.path));
+        orphans.sort_by(|a, b| a.identity.cmp(&b.identity));
+        Ok(orphans)
+    }
 
-        config.set_str(&config_value, repo_url)?;
+    /// Deletes orphaned checkouts and the git config entries pointing at
+    /// them.
+    pub fn remove_orphans(&mut self, orphans: &[manifest::Checkout]) -> Result<(), PackageRepoError> {
+        let mut failed = 0;
+        let mut reclaimed = 0;
+        for orphan in orphans {
+            info!("Removing {}", orphan.path.display());
+            let size = dir_size(&orphan.path);
+            if let Err(err) = std::fs::remove_dir_all(&orphan.path) {
+                log::error!("Failed to remove {}: {}", orphan.path.display(), err);
+                failed += 1;
+                continue;
+            }
+            reclaimed += size;
+            self.remove_global_git_proxy(&orphan.path.display().to_string())?;
+        }
+        let installed = self.dir.join(manifest::INSTALLED_FILE);
+        if let Ok(mut manifest) = Manifest::read(&installed) {
+            manifest.checkouts.retain(|checkout| checkout.path.exists());
+            manifest.write(&installed)?;
+        }
+        info!(
+            "Reclaimed {} from {} checkout(s)",
+            format_bytes(reclaimed),
+            orphans.len() - failed
+        );
 
+        if failed > 0 {
+            return Err(PackageRepoError::WipeIncomplete(failed));
+        }
         Ok(())
     }
 
-    fn remove_global_git_proxy(proxy_path: &str) -> Result<(), PackageRepoError> {
-       
-        let config_value = format!("url.{}.insteadOf", proxy_path);
-        
-        let mut config =  Config::open_default()?;
-
-        if config.get_entry(&config_value).is_ok() {
-            config.remove(&config_value)?;
+    /// Removes the checkouts that no pin in the resolved files under `path`
+    /// uses any more, or only lists them with `dry_run`.
+    pub fn gc(&mut self, path: &path::Path, dry_run: bool) -> Result<(), PackageRepoError> {
+        let orphans = self.orphans(path)?;
+        if orphans.is_empty() {
+            info!("Every checkout is used by {}", path.display());
+            return Ok(());
+        }
+        if !dry_run {
+            return self.remove_orphans(&orphans);
         }
 
+        let mut total = 0;
+        for orphan in &orphans {
+            let size = dir_size(&orphan.path);
+            total += size;
+            println!(
+                "{}\t{}\t{}",
+                orphan.identity,
+                orphan.path.display(),
+                format_bytes(size)
+            );
+        }
+        info!(
+            "Would reclaim {} from {} checkout(s)",
+            format_bytes(total),
+            orphans.len()
+        );
         Ok(())
     }
+
+    /// The repositories in the content-addressed store that the git config
+    /// redirects to.
+    fn cas_checkouts(&self, config: &git2::Config) -> Result<Vec<path::PathBuf>, PackageRepoError> {
+        let Some(cas_dir) = &self.cas_dir else {
+            return Ok(Vec::new());
+        };
+
+        let mut paths: Vec<path::PathBuf> = git_config::proxy_entries_under(config, cas_dir)?
+            .into_iter()
+            .map(|proxy| proxy.path)
+            .filter(|path| path.exists())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+
+    /// Packs every checkout as a git bundle, together with the manifest, into
+    /// a zip archive at `out` that `unbundle` can restore without network
+    /// access.
+    pub fn bundle(&self, out: &path::Path) -> Result<(), PackageRepoError> {
+        if bundle::is_tar_name(out) {
+            return Err(PackageRepoError::Bundle(format!(
+                "{} names a tar archive, but bundles are zip archives. Use a .zip name instead",
+                out.display()
+            )));
+        }
+        let staging = Staging::new()?;
+        let mut manifest = self.manifest()?;
+        manifest.checkouts.retain(|checkout| {
+            if checkout.head.is_none() {
+                warn!("Skipping {} as it is not a git repository", checkout.identity);
+            }
+            checkout.head.is_some()
+        });
+
+        for checkout in &manifest.checkouts {
+            info!("Bundling {}", checkout.identity);
+            bundle::create(&checkout.path, &staging.bundle_path(&checkout.identity))?;
+        }
+
+        manifest.write(&staging.path().join(bundle::MANIFEST_FILE))?;
+        zip_extensions::zip_create_from_directory(
+            &out.to_path_buf(),
+            &staging.path().to_path_buf(),
+        )?;
+        info!(
+            "Bundled {} checkout(s) into {}",
+            manifest.checkouts.len(),
+            out.display()
+        );
+        Ok(())
+    }
+
+    /// Restores the checkouts in an archive created by `bundle` and points the
+    /// git config at them. Checkouts that already exist are left alone.
+    pub fn unbundle(&mut self, archive: &path::Path) -> Result<(), PackageRepoError> {
+        let staging = Staging::new()?;
+        zip_extensions::zip_extract(&archive.to_path_buf(), &staging.path().to_path_buf())?;
+        let manifest = Manifest::read(&staging.path().join(bundle::MANIFEST_FILE))?;
+
+        for checkout in &manifest.checkouts {
+            let path = self.restore_path(checkout);
+            if path.exists() {
+                warn!("{} already exists, leaving it as is", path.display());
+            } else {
+                info!("Restoring {}", checkout.identity);
+                bundle::clone(
+                    &staging.bundle_path(&checkout.identity),
+                    &path,
+                    self.is_bare(),
+                )?;
+
+                let repo = git2::Repository::open(&path)?;
+                // Fetch from the original remote again once the network is
+                // available.
+                if let Some(location) = &checkout.location {
+                    repo.remote_set_url(DEFAULT_REMOTE, location)?;
+                }
+                if let Some(head) = &checkout.head {
+                    let oid = git2::Oid::from_str(head)?;
+                    if !repo.is_bare() {
+                        repo.checkout_tree(&repo.find_object(oid, None)?, None)?;
+                    }
+                    repo.set_head_detached(oid)?;
+                }
+            }
+
+            match &checkout.proxy {
+                Some(url) => self.set_global_git_proxy(url, &path.display().to_string())?,
+                None => warn!(
+                    "{} has no proxy entry in the manifest, not redirecting any url to it",
+                    checkout.identity
+                ),
+            }
+        }
+
+        self.config.get_mut().unwrap().commit()
+    }
+
+    /// Where `unbundle` restores `checkout`: the path `install` uses for the
+    /// url redirected to it, which is in the store with a `--cas-dir`.
+    fn restore_path(&self, checkout: &manifest::Checkout) -> path::PathBuf {
+        let Some(location) = checkout.proxy.as_ref().or(checkout.location.as_ref()) else {
+            return self.checkouts_dir().join(&checkout.identity);
+        };
+        self.checkout_path(&v2::Pin {
+            identity: checkout.identity.clone(),
+            kind: v2::Kind::RemoteSourceControl,
+            location: location.clone(),
+            state: v2::State {
+                branch: None,
+                revision: checkout.head.clone().unwrap_or_default(),
+                version: None,
+                checksum: None,
+            },
+        })
+    }
+
+    /// Compares the current cache against a previously exported manifest and
+    /// fails if anything changed.
+    pub fn verify_against(&self, manifest_path: &path::Path) -> Result<(), PackageRepoError> {
+        let expected = Manifest::read(manifest_path)?;
+        let drift = expected.diff(&self.manifest()?);
+
+        if drift.is_empty() {
+            info!("Cache matches {}", manifest_path.display());
+            return Ok(());
+        }
+
+        for difference in &drift {
+            println!("{}", difference);
+        }
+        Err(PackageRepoError::Drift(drift.len()))
+    }
+
+    /// Checks that the commit at each pinned revision, or locked one, carries a
+    /// valid gpg signature, fetching the revision first when the checkout
+    /// doesn't have it yet. Fails if any pin is unsigned or badly signed.
+    pub fn verify_signatures(
+        &self,
+        path: &path::Path,
+        keyring: Option<&path::Path>,
+    ) -> Result<(), PackageRepoError> {
+        let mut failures = 0;
+        for pin in Self::installed_pins(path)? {
+            if pin.kind != v2::Kind::RemoteSourceControl {
+                continue;
+            }
+
+            let status = self.signature_status(&pin, keyring)?;
+            println!("{}\t{}\t{}", pin.identity, pin.state.revision, status);
+            if !status.is_good() {
+                failures += 1;
+            }
+        }
+
+        if failures > 0 {
+            return Err(PackageRepoError::Unverified(failures));
+        }
+        Ok(())
+    }
+
+    /// Checks that every proxy entry pointing into the checkouts directory, or
+    /// the store, leads to a git repository with a valid HEAD whose remote is
+    /// for the redirected url, printing one line per entry. With `fix`,
+    /// entries that point at no checkout are removed. Fails if any broken
+    /// entry is left.
+    pub fn verify_proxies(&self, fix: bool) -> Result<(), PackageRepoError> {
+        let config = self.proxy_config()?;
+        let mut proxies = git_config::proxy_entries_under(&config, &self.checkouts_dir())?;
+        if let Some(cas_dir) = &self.cas_dir {
+            proxies.extend(git_config::proxy_entries_under(&config, cas_dir)?);
+        }
+
+        let mut broken = 0;
+        for proxy in &proxies {
+            let health = proxy_health(proxy);
+            println!("{}\t{}\t{}", proxy.path.display(), proxy.url, health.name());
+            if health == ProxyHealth::Ok {
+                continue;
+            }
+            if fix && health.is_dangling() {
+                info!("Removing {} = {}", proxy.key, proxy.url);
+                let mut transaction = self.config.lock().unwrap();
+                transaction.remove_value(&proxy.key, &proxy.url)?;
+                transaction.remove(&git_config::managed_key(&proxy.path.display().to_string()))?;
+                continue;
+            }
+            broken += 1;
+        }
+
+        if broken > 0 {
+            return Err(PackageRepoError::BrokenProxies(broken));
+        }
+        Ok(())
+    }
+
+    /// Compares the checkout of every git package pinned under `path` to its
+    /// pinned revision, or locked one, and the git config, printing one line
+    /// or JSON object per pin. Fails if any of them is out of sync.
+    pub fn status(&self, path: &path::Path, format: OutputFormat) -> Result<(), PackageRepoError> {
+        let config = self.proxy_config()?;
+        let proxies = git_config::proxy_entries(&config)?;
+
+        let mut out_of_sync = 0;
+        for pin in Self::installed_pins(path)? {
+            if pin.kind != v2::Kind::RemoteSourceControl {
+                continue;
+            }
+
+            let checkout = self.checkout_path(&pin);
+            let status = match git2::Repository::open(&checkout) {
+                Err(_) => SyncStatus::Missing,
+                Ok(repo) => {
+                    let head = repo
+                        .head()
+                        .and_then(|head| head.peel_to_commit())
+                        .map(|commit| commit.id().to_string())
+                        .ok();
+                    let swapped = proxies
+                        .iter()
+                        .any(|proxy| proxy.path == checkout && proxy.url == pin.location);
+                    // A followed branch is wherever the last install left it.
+                    let followed = pin.state.followed_branch().is_some();
+                    match head {
+                        Some(head) if followed || head == pin.state.revision => {
+                            if swapped {
+                                SyncStatus::Ok
+                            } else {
+                                SyncStatus::NotSwapped
+                            }
+                        }
+                        head => SyncStatus::WrongRevision(head),
+                    }
+                }
+            };
+
+            let pinned = pin.state.followed_branch().unwrap_or(&pin.state.revision);
+            match format {
+                OutputFormat::Text => println!("{}\t{}\t{}", pin.identity, pinned, status),
+                OutputFormat::JsonLines => output::emit(&StatusEvent {
+                    identity: &pin.identity,
+                    location: &pin.location,
+                    action: "status",
+                    revision: pinned,
+                    result: status.name(),
+                    head: match &status {
+                        SyncStatus::WrongRevision(head) => head.clone(),
+                        _ => None,
+                    },
+                }),
+            }
+            if status != SyncStatus::Ok {
+                out_of_sync += 1;
+            }
+        }
+
+        if out_of_sync > 0 {
+            return Err(PackageRepoError::OutOfSync(out_of_sync));
+        }
+        Ok(())
+    }
+
+    /// Lists the URL each git package would be cloned from and the checkout
+    /// path it would be cloned into, so the network step can be performed by
+    /// another tool.
+    pub fn export_urls(
+        &self,
+        path: &path::Path,
+        url_rewriter: &UrlRewriter,
+    ) -> Result<Vec<(String, path::PathBuf)>, PackageRepoError> {
+        Ok(parse_all_recursive(path)?
+            .iter()
+            .filter(|pin| pin.kind == v2::Kind::RemoteSourceControl)
+            .map(|pin| (url_rewriter.rewrite(pin), self.checkout_path(pin)))
+            .collect())
+    }
+
+    pub fn install(
+        &mut self,
+        path: &path::Path,
+        options: &InstallOptions,
+    ) -> Result<(), PackageRepoError> {
+        let started = Instant::now();
+        self.metrics = Mutex::new(Metrics::default());
+        self.summary = Mutex::new(Summary::default());
+        let result = self.install_pins(path, options);
+
+        let summary = self.summary.get_mut().unwrap();
+        summary.log();
+        if let Some(summary_json) = &options.summary_json {
+            if let Err(error) = summary.write(summary_json) {
+                warn!(
+                    "Failed to write the summary to {}: {}",
+                    summary_json.display(),
+                    error
+                );
+            }
+        }
+
+        if let Some(metrics_file) = &options.metrics_file {
+            if let Err(error) = self
+                .metrics
+                .get_mut()
+                .unwrap()
+                .write(metrics_file, started.elapsed(), result.is_ok())
+            {
+                warn!(
+                    "Failed to write metrics to {}: {}",
+                    metrics_file.display(),
+                    error
+                );
+            }
+        }
+
+        result
+    }
+
+    fn install_pins(
+        &mut self,
+        path: &path::Path,
+        options: &InstallOptions,
+    ) -> Result<(), PackageRepoError> {
+        // Fail before cloning anything rather than when the config is written.
+        if let Some(local) = &self.local_config {
+            git2::Repository::open(local)?;
+        }
+        info!("Scanning directory: {:?} for Package.resovled", path);
+        let changed = options.changed_files_from.as_deref().and_then(|reference| {
+            changed_resolved_files(path, reference)
+                .inspect_err(|err| {
+                    warn!(
+                        "Could not diff against {}, scanning every resolved file: {}",
+                        reference, err
+                    )
+                })
+                .ok()
+        });
+        let partial = changed.is_some();
+        let mut files = match changed {
+            Some(changed) => {
+                info!(
+                    "{} resolved file(s) changed since {}",
+                    changed.len(),
+                    options.changed_files_from.as_deref().unwrap_or_default()
+                );
+                changed
+                    .into_iter()
+                    .map(|file| Ok((file.clone(), resolved::parse(&file)?)))
+                    .collect::<Result<Vec<_>, PackageRepoError>>()?
+            }
+            None => resolved::parse_files_with(path, options.scan_jobs)?,
+        };
+
+        for diagnostic in diagnostics::validate(&files) {
+            diagnostic.emit(options.diagnostics_format);
+        }
+        if let Some(policy) = options.require_https {
+            Self::require_https(&mut files, policy, options.diagnostics_format)?;
+        }
+
+        let (pins, previous) = Self::locked_pins(
+            path,
+            resolved::merge_with(files, options.dedupe_strategy)?,
+            // Updating means moving to the resolved revisions, which a
+            // lockfile would otherwise pin in place.
+            options.update_lock || options.existing_only,
+            // A partial scan would drop every other pin from the lockfile.
+            !options.check && !options.dry_run && !partial && options.emit_script.is_none(),
+        )?;
+        // The lockfile keeps every pin, only the selected ones are installed.
+        let pins = Self::filter_pins(pins, options);
+        let previous = previous.map(|mut previous| {
+            previous.pins.retain(|pin| Self::is_selected(pin, options));
+            previous
+        });
+
+        if options.check {
+            return self.check(&pins, options);
+        }
+        if let Some(script) = &options.emit_script {
+            self.options = options.clone();
+            return self.emit_script(&pins, script);
+        }
+        if options.dry_run {
+            self.options = options.clone();
+            return self.dry_run(&pins);
+        }
+
+        self.config = Mutex::new(
+            ConfigTransaction::new(options.no_transaction && !options.print_config)
+                .with_local(self.local_config.clone()),
+        );
+        self.options = options.clone();
+
+        let changes = previous
+            .map(|previous| lock::changes(&previous, &pins))
+            .unwrap_or_default();
+        self.report_changes(&changes)?;
+        self.deadline = options.deadline.map(|deadline| Instant::now() + deadline);
+
+        self.check_free_space()?;
+
+        if let Some(token) = Token::from_env() {
+            Self::report_token(&token, &pins, &options.url_rewriter);
+        }
+
+        let hosts = if options.preflight {
+            let timeout = options.timeout.unwrap_or(DEFAULT_PREFLIGHT_TIMEOUT);
+            preflight::check_hosts(
+                &self.authenticator(),
+                &options.url_rewriter,
+                options.http_proxy.as_deref(),
+                &pins,
+                timeout,
+            )
+        } else {
+            BTreeMap::new()
+        };
+
+        let failed_hosts = hosts.values().filter(|status| !status.is_ok()).count();
+        if failed_hosts > 0 {
+            warn!(
+                "Preflight failed for {} host(s). Packages on those hosts will be skipped",
+                failed_hosts
+            );
+        }
+
+        let progress = Progress::new(pins.len(), options.verbose);
+        let max_level = log::max_level();
+        if progress.is_interactive() {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+        // JSON events go to stdout, keep the line to the package counts.
+        self.status_line = progress
+            .bar()
+            .filter(|_| options.format == OutputFormat::Text);
+
+        let jobs = options.jobs.max(1);
+        // Set when the install is aborted, or with `--fail-fast` once a
+        // package has failed, so no further packages are started.
+        let abort = AtomicBool::new(false);
+        let outcomes = std::thread::scope(|scope| {
+            // A slot is handed back as each package finishes, so at most
+            // `jobs` are installed at once.
+            let (release, acquire) = mpsc::channel();
+            for _ in 0..jobs {
+                let _ = release.send(());
+            }
+
+            let mut workers = Vec::new();
+            for pin in &pins {
+                let _ = acquire.recv();
+                if abort.load(Ordering::SeqCst) {
+                    break;
+                }
+                let release = release.clone();
+                let (this, hosts, progress, abort) = (&*self, &hosts, &progress, &abort);
+                // Named after the package so log lines of packages installed
+                // in parallel can be told apart.
+                let worker = std::thread::Builder::new()
+                    .name(pin.identity.clone())
+                    .spawn_scoped(scope, move || {
+                        let outcome = this.install_pin(pin, hosts, progress, abort);
+                        if this.options.fail_fast
+                            && matches!(outcome, PinOutcome::Failed | PinOutcome::TimedOut(_))
+                        {
+                            abort.store(true, Ordering::SeqCst);
+                        }
+                        let _ = release.send(());
+                        outcome
+                    });
+                match worker {
+                    Ok(worker) => workers.push(worker),
+                    Err(error) => return vec![PinOutcome::Aborted(error.into())],
+                }
+            }
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("package install thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut completed = 0;
+        let mut failed = 0;
+        let mut skipped = 0;
+        let mut timed_out = Vec::new();
+        let mut aborted = None;
+        let mut unreached = pins.len() - outcomes.len();
+        for outcome in outcomes {
+            match outcome {
+                PinOutcome::Completed => completed += 1,
+                PinOutcome::Failed => failed += 1,
+                PinOutcome::TimedOut(identity) => {
+                    failed += 1;
+                    timed_out.push(identity);
+                }
+                PinOutcome::Skipped => skipped += 1,
+                PinOutcome::Unreached => unreached += 1,
+                PinOutcome::Aborted(error) => {
+                    aborted.get_or_insert(error);
+                }
+            }
+        }
+        if let Some(error) = aborted {
+            log::set_max_level(max_level);
+            progress.complete();
+            self.status_line = None;
+            self.config.get_mut().unwrap().rollback();
+            return Err(error);
+        }
+
+        log::set_max_level(max_level);
+        progress.complete();
+        self.status_line = None;
+        if unreached > 0 {
+            warn!(
+                "Stopped after the first failure as --fail-fast was given, {} package(s) were not installed",
+                unreached
+            );
+        }
+        if !timed_out.is_empty() {
+            timed_out.sort();
+            warn!(
+                "{} package(s) timed out: {}",
+                timed_out.len(),
+                timed_out.join(", ")
+            );
+        }
+
+        // Config for the packages that did finish is still applied below.
+        let deadline_exceeded = (skipped > 0).then_some(PackageRepoError::DeadlineExceeded {
+            completed: completed + failed,
+            skipped,
+        });
+        let outcome = if failed > 0 && !options.keep_going {
+            Some(PackageRepoError::InstallFailed(failed))
+        } else {
+            if failed > 0 {
+                warn!(
+                    "{} package(s) failed to install, continuing as --keep-going was given",
+                    failed
+                );
+            }
+            deadline_exceeded
+        };
+
+        let config = self.config.get_mut().unwrap();
+        if options.print_config {
+            print!("{}", git_config::render_fragment(config.staged()));
+            config.rollback();
+            return outcome.map_or(Ok(()), Err);
+        }
+
+        if let Err(error) = config.commit() {
+            config.rollback();
+            return Err(error);
+        }
+
+        let installed = self.dir.join(manifest::INSTALLED_FILE);
+        if let Err(error) = self.write_installed(&installed, &pins) {
+            warn!(
+                "Failed to write the manifest to {}: {}",
+                installed.display(),
+                error
+            );
+        }
+
+        outcome.map_or(Ok(()), Err)
+    }
+
+    /// Records every checkout on disk in `path`, with the revision and
+    /// version pinned for it. Checkouts of packages that weren't part of this
+    /// run keep what the previous manifest recorded, as long as their HEAD
+    /// hasn't moved since.
+    fn write_installed(&self, path: &path::Path, pins: &[v2::Pin]) -> Result<(), PackageRepoError> {
+        let previous: BTreeMap<path::PathBuf, manifest::Checkout> = Manifest::read(path)
+            .map(|previous| {
+                previous
+                    .checkouts
+                    .into_iter()
+                    .map(|checkout| (checkout.path.clone(), checkout))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let pinned: BTreeMap<path::PathBuf, &v2::Pin> = pins
+            .iter()
+            .filter(|pin| pin.kind == v2::Kind::RemoteSourceControl)
+            .map(|pin| (self.checkout_path(pin), pin))
+            .collect();
+
+        let mut manifest = self.manifest()?;
+        for checkout in &mut manifest.checkouts {
+            if let Some(pin) = pinned.get(&checkout.path) {
+                // A checkout that failed to move keeps no claim to the pin.
+                if checkout.head.as_deref() == Some(&pin.state.revision) {
+                    checkout.revision = Some(pin.state.revision.clone());
+                    checkout.version = pin.state.version.clone();
+                }
+            } else if let Some(previous) = previous.get(&checkout.path) {
+                if previous.head == checkout.head {
+                    checkout.revision = previous.revision.clone();
+                    checkout.version = previous.version.clone();
+                }
+            }
+        }
+        manifest.write(path)
+    }
+}
+
+impl PackageRepo {
+    /// Installs a single package, on its own thread when several are
+    /// installed in parallel.
+    fn install_pin(
+        &self,
+        pin: &v2::Pin,
+        hosts: &BTreeMap<String, preflight::HostStatus>,
+        progress: &Progress,
+        abort: &AtomicBool,
+    ) -> PinOutcome {
+        if abort.load(Ordering::SeqCst) {
+            return PinOutcome::Unreached;
+        }
+        if self.deadline_passed() {
+            info!("Skipping {} as the deadline has passed", pin.identity);
+            progress.finish(&pin.identity, false);
+            self.report(
+                pin,
+                Action::Skipped,
+                Some(String::from("deadline exceeded")),
+                None,
+            );
+            return PinOutcome::Skipped;
+        }
+
+        progress.start(&pin.identity);
+
+        let host = url::host(&self.options.url_rewriter.rewrite(pin));
+        if let Some(status) = host.as_ref().and_then(|host| hosts.get(host)) {
+            if !status.is_ok() {
+                log::error!(
+                    "Skipping {} as its host failed preflight: {:?}",
+                    pin.identity,
+                    status
+                );
+                progress.finish(&pin.identity, false);
+                self.report(
+                    pin,
+                    Action::Failed,
+                    Some(format!("host failed preflight: {:?}", status)),
+                    None,
+                );
+                return PinOutcome::Failed;
+            }
+        }
+
+        info!("Cloning: {:?}", pin.identity);
+        let result = self
+            .clone(pin)
+            .and_then(|action| self.run_checkout_hook(pin, action))
+            .map_err(|error| {
+                if error.is_disk_full() {
+                    PackageRepoError::DiskFull(pin.identity.clone())
+                } else {
+                    error
+                }
+            });
+        if let Err(
+            error @ (PackageRepoError::DiskFull(_) | PackageRepoError::LowDiskSpace { .. }),
+        ) = result
+        {
+            log::error!("{}. Aborting install as every remaining clone would fail", error);
+            abort.store(true, Ordering::SeqCst);
+            progress.finish(&pin.identity, false);
+            self.report(pin, Action::Failed, Some(error.to_string()), None);
+            return PinOutcome::Aborted(error);
+        }
+        if result.is_err() && self.deadline_passed() {
+            warn!("Cancelled {} as the deadline passed", pin.identity);
+            progress.finish(&pin.identity, false);
+            self.report(
+                pin,
+                Action::Skipped,
+                Some(String::from("deadline exceeded")),
+                None,
+            );
+            return PinOutcome::Skipped;
+        }
+        if let Err(error) = &result {
+            log::error!(
+                "Error cloning {} at: {}. {}",
+                pin.identity,
+                pin.location,
+                error,
+            );
+        }
+        progress.finish(&pin.identity, result.is_ok());
+        match result {
+            Ok((action, hook_output)) => {
+                self.report(pin, action, None, hook_output);
+                PinOutcome::Completed
+            }
+            Err(error @ PackageRepoError::TimedOut(_)) => {
+                self.report(pin, Action::Failed, Some(error.to_string()), None);
+                PinOutcome::TimedOut(pin.identity.clone())
+            }
+            Err(error) => {
+                self.report(pin, Action::Failed, Some(error.to_string()), None);
+                PinOutcome::Failed
+            }
+        }
+    }
+
+    /// Writes the `git clone`, `git fetch`, `git checkout` and `git config`
+    /// commands equivalent to installing `pins` to a shell script, without
+    /// running any of them.
+    fn emit_script(&self, pins: &[v2::Pin], path: &path::Path) -> Result<(), PackageRepoError> {
+        let mut script = Script::new();
+        let remote = self.remote_name();
+        let local_config = self
+            .local_config
+            .as_ref()
+            .map(|local| local.display().to_string());
+        let scope = match &local_config {
+            Some(local) => vec!["-C", local, "config", "--local"],
+            None => vec!["config", "--global"],
+        };
+
+        for pin in pins {
+            if pin.kind != v2::Kind::RemoteSourceControl {
+                script.comment(&format!("Skipping {} as it is not a git repo", pin.identity));
+                continue;
+            }
+
+            let checkout = self.checkout_path(pin).display().to_string();
+            script.comment(&format!(
+                "{} {}",
+                pin.identity,
+                pin.state
+                    .version
+                    .as_deref()
+                    .or(pin.state.followed_branch())
+                    .unwrap_or(&pin.state.revision)
+            ));
+
+            if !self.options.offline {
+                if git2::Repository::open(&checkout).is_ok() {
+                    let mut args = vec![
+                        "-C",
+                        &checkout,
+                        "fetch",
+                        remote,
+                        "refs/heads/*:refs/heads/*",
+                    ];
+                    let branch_refspec = self.branch_refspec(self.is_bare(), pin);
+                    args.extend(branch_refspec.as_deref());
+                    script.git(&args);
+                } else {
+                    let repo_url = self.options.url_rewriter.rewrite(pin);
+                    let mut args = vec!["clone", "--quiet", "--origin", remote];
+                    if self.is_bare() {
+                        args.push("--bare");
+                    }
+                    script.git(&[args, vec![&repo_url, &checkout]].concat());
+                }
+            }
+
+            // Bare clones have no work tree.
+            match (self.options.checkout_by, &pin.state.branch) {
+                _ if self.is_bare() => {}
+                (_, Some(branch)) if pin.state.followed_branch().is_some() => script.git(&[
+                    "-C",
+                    &checkout,
+                    "checkout",
+                    "--quiet",
+                    "-B",
+                    branch,
+                    "--track",
+                    &format!("{}/{}", remote, branch),
+                ]),
+                (CheckoutBy::Branch, Some(branch)) => script.git(&[
+                    "-C",
+                    &checkout,
+                    "checkout",
+                    "--quiet",
+                    "-B",
+                    branch,
+                    &pin.state.revision,
+                ]),
+                _ => script.git(&[
+                    "-C",
+                    &checkout,
+                    "checkout",
+                    "--quiet",
+                    "--detach",
+                    &pin.state.revision,
+                ]),
+            }
+
+            for variable in KeyStyle::Both.variables() {
+                let key = git_config::proxy_key_with(&checkout, variable);
+                script.git_allow_failure(&[&scope[..], &["--unset-all", &key]].concat());
+            }
+            for variable in self.options.key_style.variables() {
+                let key = git_config::proxy_key_with(&checkout, variable);
+                script.git(&[&scope[..], &[&key, &pin.location]].concat());
+            }
+            let managed_key = git_config::managed_key(&checkout);
+            script.git(&[&scope[..], &[&managed_key, &pin.location]].concat());
+        }
+        if !pins.is_empty() {
+            script.git(&[&scope[..], &[git_config::MARKS_VALUES, "true"]].concat());
+        }
+
+        script.write(path)?;
+        info!("Wrote the git commands for {} package(s) to {}", pins.len(), path.display());
+        Ok(())
+    }
+
+    /// Prints what installing `pins` would do to each package and the git
+    /// config entries it would write, without doing any of it.
+    fn dry_run(&self, pins: &[v2::Pin]) -> Result<(), PackageRepoError> {
+        for pin in pins {
+            if pin.kind == v2::Kind::BinaryTarget {
+                let path = self.dir.join(BINARIES_DIR).join(&pin.identity);
+                let url = self.options.url_rewriter.rewrite_download(pin);
+                println!("download\t{}\t{}\t{}", pin.identity, url, path.display());
+                continue;
+            }
+            if pin.kind == v2::Kind::LocalSourceControl {
+                let path = path::Path::new(&pin.location)
+                    .canonicalize()
+                    .ok()
+                    .filter(|path| git2::Repository::open(path).is_ok());
+                let Some(path) = path else {
+                    println!("skip\t{}\t{} is not a git repository", pin.identity, pin.location);
+                    continue;
+                };
+                println!("link\t{}\t{}", pin.identity, path.display());
+                for variable in self.options.key_style.variables() {
+                    let key = git_config::proxy_key_with(&path.display().to_string(), variable);
+                    println!("config\t{}\t{}", key, pin.location);
+                }
+                continue;
+            }
+            if pin.kind != v2::Kind::RemoteSourceControl {
+                println!("skip\t{}\tnot a git repo", pin.identity);
+                continue;
+            }
+
+            let repo_url = self.options.url_rewriter.rewrite(pin);
+            let path = self.checkout_path(pin);
+            let reused = self
+                .options
+                .reuse_spm_cache
+                .as_deref()
+                .and_then(|cache| find_spm_checkout(cache, pin));
+            let target = reused.clone().unwrap_or_else(|| path.clone());
+
+            // A clone that died is replaced by a fresh one, anything else in
+            // the way only with `--force`.
+            let repo = git2::Repository::open(&path)
+                .ok()
+                .filter(|_| is_usable_repo(&path));
+            match repo {
+                _ if reused.is_some() => {
+                    println!("link\t{}\t{}", pin.identity, target.display());
+                }
+                Some(_) if self.options.offline => {
+                    println!("link\t{}\t{}", pin.identity, path.display());
+                }
+                Some(repo) => {
+                    if self.reason_to_skip_fetch(&repo, pin).is_some() {
+                        println!("link\t{}\t{}", pin.identity, path.display());
+                    } else {
+                        println!(
+                            "fetch\t{}\t{}\t{}",
+                            pin.identity,
+                            url::redact(&repo_url),
+                            path.display()
+                        );
+                    }
+                }
+                None if self.options.offline => {
+                    println!("fail\t{}\tno checkout at {}", pin.identity, path.display());
+                    continue;
+                }
+                None if is_in_the_way(&path)? && !self.options.force => {
+                    println!(
+                        "fail\t{}\t{} is not a git repository",
+                        pin.identity,
+                        path.display()
+                    );
+                    continue;
+                }
+                None => {
+                    println!(
+                        "clone\t{}\t{}\t{}",
+                        pin.identity,
+                        url::redact(&repo_url),
+                        path.display()
+                    );
+                }
+            }
+
+            for variable in self.options.key_style.variables() {
+                let key = git_config::proxy_key_with(&target.display().to_string(), variable);
+                println!("config\t{}\t{}", key, pin.location);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks every pin against its remote and prints one OK or FAIL line
+    /// per pin.
+    fn check(&self, pins: &[v2::Pin], options: &InstallOptions) -> Result<(), PackageRepoError> {
+        let results = preflight::check_pins(
+            &self.authenticator(),
+            &options.url_rewriter,
+            options.http_proxy.as_deref(),
+            pins,
+            options.timeout.unwrap_or(DEFAULT_PREFLIGHT_TIMEOUT),
+        );
+
+        let mut failures = 0;
+        for (pin, status) in &results {
+            let repo_url = options.url_rewriter.rewrite(pin);
+            let reason = match status {
+                preflight::HostStatus::Ok => {
+                    println!("OK\t{}\t{}", pin.identity, url::redact(&repo_url));
+                    continue;
+                }
+                preflight::HostStatus::AuthFailed(message) => {
+                    format!("authentication failed: {}", message)
+                }
+                preflight::HostStatus::Unreachable(message) => format!("unreachable: {}", message),
+                preflight::HostStatus::TimedOut => String::from("timed out"),
+                preflight::HostStatus::RevisionNotFound => {
+                    format!("no ref points at {}", pin.state.revision)
+                }
+            };
+            println!(
+                "FAIL\t{}\t{}\t{}",
+                pin.identity,
+                url::redact(&repo_url),
+                reason
+            );
+            failures += 1;
+        }
+
+        if failures > 0 {
+            return Err(PackageRepoError::CheckFailed(failures));
+        }
+        Ok(())
+    }
+
+    fn report(
+        &self,
+        pin: &v2::Pin,
+        action: Action,
+        error: Option<String>,
+        hook_output: Option<String>,
+    ) {
+        self.metrics.lock().unwrap().record(action);
+        self.summary
+            .lock()
+            .unwrap()
+            .record(&pin.identity, action, error.as_deref());
+        if self.options.format == OutputFormat::JsonLines {
+            output::emit(&PinEvent::new(pin, action, error).with_hook_output(hook_output));
+        }
+    }
+
+    /// Runs the checkout hook for a package that was cloned, fetched or
+    /// linked. The hook's output is returned for the package's report, and a
+    /// failing hook fails the package and drops its proxy entry.
+    fn run_checkout_hook(
+        &self,
+        pin: &v2::Pin,
+        action: Action,
+    ) -> Result<(Action, Option<String>), PackageRepoError> {
+        let Some(hook) = &self.options.checkout_hook else {
+            return Ok((action, None));
+        };
+        // Binary targets are unpacked archives, not checkouts.
+        if !matches!(action, Action::Cloned | Action::Fetched | Action::Linked)
+            || pin.kind == v2::Kind::BinaryTarget
+        {
+            return Ok((action, None));
+        }
+
+        let path = match &self.options.reuse_spm_cache {
+            _ if pin.kind == v2::Kind::LocalSourceControl => {
+                path::Path::new(&pin.location).canonicalize()?
+            }
+            Some(cache) if action == Action::Linked => {
+                find_spm_checkout(cache, pin).unwrap_or_else(|| self.checkout_path(pin))
+            }
+            _ => self.checkout_path(pin),
+        };
+
+        info!("Running checkout hook for {}", pin.identity);
+        let output = shell(hook)
+            .current_dir(&path)
+            .env("SPM_GIT_SWAP_IDENTITY", &pin.identity)
+            .env("SPM_GIT_SWAP_LOCATION", &pin.location)
+            .env("SPM_GIT_SWAP_PATH", &path)
+            .env("SPM_GIT_SWAP_REVISION", &pin.state.revision)
+            .output()?;
+
+        let mut captured = String::from_utf8_lossy(&output.stdout).to_string();
+        captured.push_str(&String::from_utf8_lossy(&output.stderr));
+        let captured = captured.trim().to_string();
+        if !captured.is_empty() {
+            info!("Checkout hook output for {}: {}", pin.identity, captured);
+        }
+
+        if !output.status.success() {
+            self.remove_global_git_proxy(&path.display().to_string())?;
+            return Err(PackageRepoError::HookFailed {
+                status: output.status.to_string(),
+                output: captured,
+            });
+        }
+
+        Ok((action, Some(captured).filter(|captured| !captured.is_empty())))
+    }
+
+    fn clone(&self, pin: &v2::Pin) -> Result<Action, PackageRepoError> {
+        if pin.kind == v2::Kind::BinaryTarget {
+            return self.download_binary(pin);
+        }
+        if pin.kind == v2::Kind::LocalSourceControl {
+            return self.link_local(pin);
+        }
+        if pin.kind != v2::Kind::RemoteSourceControl {
+            info!("Skipping {} as it is not a git repo", pin.identity);
+            return Ok(Action::Skipped);
+        }
+
+        let path = self.checkout_path(pin);
+        let is_repo = is_usable_repo(&path);
+        if self.options.existing_only && !is_repo {
+            info!("Skipping {} as it has no checkout", pin.identity);
+            return Ok(Action::Skipped);
+        }
+
+        let repo_url = self.options.url_rewriter.rewrite(pin);
+
+        if repo_url != pin.location {
+            info!(
+                "Rewriting clone url for {}. Cloning from {}",
+                pin.location,
+                url::redact(&repo_url)
+            );
+        }
+
+        let version = pin
+            .state
+            .version
+            .clone()
+            .unwrap_or_else(|| String::from("NO_VERSION"));
+
+        if let Some(cache) = &self.options.reuse_spm_cache {
+            if let Some(existing) = find_spm_checkout(cache, pin) {
+                info!(
+                    "Reusing SwiftPM checkout of {} at {}",
+                    pin.identity,
+                    existing.display()
+                );
+                self.remove_global_git_proxy(&path.display().to_string())?;
+                self.set_global_git_proxy(&pin.location, &existing.display().to_string())?;
+                self.touch(&existing)?;
+                return Ok(Action::Linked);
+            }
+        }
+
+        if !is_repo && !self.options.offline && !self.options.force && is_in_the_way(&path)? {
+            return Err(PackageRepoError::NotARepository(path));
+        }
+
+        self.remove_global_git_proxy(&path.display().to_string())?;
+        self.lift_own_redirect(&path.display().to_string())?;
+
+        if self.options.offline {
+            if !is_repo {
+                return Err(PackageRepoError::MissingCheckout(path));
+            }
+            info!("Offline, using existing checkout for {}", pin.identity);
+            self.set_global_git_proxy(&pin.location, &path.display().to_string())?;
+            self.touch(&path)?;
+            return Ok(Action::Linked);
+        }
+
+        if !is_repo && path.exists() && !is_empty_dir(&path)? {
+            if is_stale_clone(&path) {
+                warn!(
+                    "{} holds a clone that didn't finish, removing it and cloning again",
+                    path.display()
+                );
+            } else {
+                warn!(
+                    "{} exists and is not a git repository, removing it",
+                    path.display()
+                );
+            }
+            if path.is_dir() {
+                std::fs::remove_dir_all(&path)?;
+            } else {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        self.check_free_space()?;
+
+        if is_repo {
+            let repo = git2::Repository::open(&path)?;
+
+            if let Some(reason) = self.reason_to_skip_fetch(&repo, pin) {
+                info!("{}", reason);
+                let before = head_commit(&repo);
+                checkout::checkout(&repo, pin, self.options.checkout_by, self.remote_name())?;
+                if let Some(before) = before.filter(|before| *before != pin.state.revision) {
+                    self.summary
+                        .lock()
+                        .unwrap()
+                        .moved(&pin.identity, &before, &pin.state.revision);
+                }
+                self.set_global_git_proxy(&pin.location, &path.display().to_string())?;
+                self.touch(&path)?;
+                return Ok(Action::Linked);
+            }
+
+            info!("{} already exists, fetching", pin.identity);
+
+            self.reconcile_remote_url(&repo, &repo_url)?;
+            let remote = repo.find_remote(self.remote_name())?;
+
+            let before = head_commit(&repo);
+            let mut refspecs = vec![String::from("refs/heads/*:refs/heads/*")];
+            refspecs.extend(self.branch_refspec(repo.is_bare(), pin));
+            let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+            self.fetch(&repo, &remote, &refspecs)?;
+            let followed = self.follow_branch(&repo, pin)?;
+            self.fetch_missing_revision(&repo, &followed)?;
+            mark_fetched(&repo)?;
+            checkout::checkout(&repo, &followed, self.checkout_by(pin), self.remote_name())?;
+            if let Some(before) = before.filter(|before| *before != followed.state.revision) {
+                self.summary.lock().unwrap().moved(
+                    &pin.identity,
+                    &before,
+                    &followed.state.revision,
+                );
+            }
+
+            self.set_global_git_proxy(&pin.location, &path.display().to_string())?;
+            self.touch(&path)?;
+
+            return Ok(Action::Fetched);
+        } else {
+            info!("Cloning {} at {}", pin.identity, pin.location);
+        }
+
+        let mirror = url::host(&pin.location).and_then(|host| self.options.host_mirrors.get(&host));
+        if let Some(mirror) = mirror {
+            info!("Borrowing objects for {} from {}", pin.identity, mirror.display());
+        }
+
+        let revision = self
+            .clone_repo(&repo_url, &path, mirror.map(path::PathBuf::as_path))
+            .and_then(|repo| {
+                let followed = self.follow_branch(&repo, pin)?;
+                self.fetch_missing_revision(&repo, &followed)?;
+                mark_fetched(&repo)?;
+                checkout::checkout(&repo, &followed, self.checkout_by(pin), self.remote_name())?;
+                Ok(followed.state.revision)
+            })
+            .inspect_err(|_| {
+                if path.exists() {
+                    info!("Removing {} due to error cloning", path.display());
+                    if let Err(deleter_error) = std::fs::remove_dir_all(&path) {
+                        log::error!(
+                            "Error deleting {} after error cloning: {}. You may need to manually delete this directory.",
+                            path.display(),
+                            deleter_error
+                        );
+                    }
+                }
+            })?;
+
+        info!(
+            "Cloned {} , version {} at revision: {}",
+            pin.identity, version, revision
+        );
+
+        info!(
+            "Setting global git proxy for {} to {}",
+            pin.location,
+            &path.display()
+        );
+        self.set_global_git_proxy(&pin.location, &path.display().to_string())?;
+
+        Ok(Action::Cloned)
+    }
+
+    /// Points the git config at the repository a local pin names, so it is
+    /// redirected like every other package. There is nothing to clone.
+    fn link_local(&self, pin: &v2::Pin) -> Result<Action, PackageRepoError> {
+        let path = path::Path::new(&pin.location);
+        if !path.exists() {
+            warn!(
+                "Skipping {} as its local path {} does not exist",
+                pin.identity, pin.location
+            );
+            return Ok(Action::Skipped);
+        }
+        if git2::Repository::open(path).is_err() {
+            warn!(
+                "Skipping {} as {} is not a git repository",
+                pin.identity, pin.location
+            );
+            return Ok(Action::Skipped);
+        }
+
+        let path = path.canonicalize()?;
+        info!("Linking {} to {}", pin.identity, path.display());
+        self.set_global_git_proxy(&pin.location, &path.display().to_string())?;
+        Ok(Action::Linked)
+    }
+
+    /// Downloads a binary target's zip archive, checks it against the pinned
+    /// checksum if there is one and unpacks it into the binaries directory.
+    /// An archive already unpacked from the same location and checksum is
+    /// reused.
+    fn download_binary(&self, pin: &v2::Pin) -> Result<Action, PackageRepoError> {
+        let binaries_dir = self.dir.join(BINARIES_DIR);
+        let path = binaries_dir.join(&pin.identity);
+        let stamp = format!(
+            "{}\n{}\n",
+            pin.location,
+            pin.state.checksum.as_deref().unwrap_or_default()
+        );
+
+        if self.options.existing_only && !path.exists() {
+            info!("Skipping {} as it isn't downloaded", pin.identity);
+            return Ok(Action::Skipped);
+        }
+        let unpacked = std::fs::read_to_string(path.join(binary::MARKER_FILE))
+            .is_ok_and(|existing| existing == stamp);
+        if unpacked {
+            info!("{} is already unpacked at {}", pin.identity, path.display());
+            return Ok(Action::Linked);
+        }
+        if self.options.offline {
+            return Err(PackageRepoError::MissingCheckout(path));
+        }
+
+        self.check_free_space()?;
+        let url = self.options.url_rewriter.rewrite_download(pin);
+        info!("Downloading {} from {}", pin.identity, url);
+
+        let staging = binaries_dir.join(format!(".{}.download", pin.identity));
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+        let result = self.unpack_binary(pin, &url, &staging, &path, &stamp);
+        let _ = std::fs::remove_dir_all(&staging);
+        result?;
+
+        info!("Unpacked {} to {}", pin.identity, path.display());
+        Ok(Action::Cloned)
+    }
+
+    fn unpack_binary(
+        &self,
+        pin: &v2::Pin,
+        url: &str,
+        staging: &path::Path,
+        path: &path::Path,
+        stamp: &str,
+    ) -> Result<(), PackageRepoError> {
+        let archive = staging.join("archive.zip");
+        binary::download(
+            url,
+            &archive,
+            self.options.http_proxy.as_deref(),
+            self.options.timeout,
+        )?;
+
+        match &pin.state.checksum {
+            Some(expected) => {
+                let actual = binary::checksum(&archive)?;
+                if !actual.eq_ignore_ascii_case(expected) {
+                    return Err(PackageRepoError::ChecksumMismatch {
+                        identity: pin.identity.clone(),
+                        expected: expected.clone(),
+                        actual,
+                    });
+                }
+            }
+            None => warn!(
+                "{} has no checksum, unpacking it without verification",
+                pin.identity
+            ),
+        }
+
+        let unpacked = staging.join("unpacked");
+        zip_extensions::zip_extract(&archive, &unpacked)?;
+        std::fs::write(unpacked.join(binary::MARKER_FILE), stamp)?;
+
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+        std::fs::rename(&unpacked, path)?;
+        Ok(())
+    }
+
+    fn signature_status(
+        &self,
+        pin: &v2::Pin,
+        keyring: Option<&path::Path>,
+    ) -> Result<SignatureStatus, PackageRepoError> {
+        let path = self.checkout_path(pin);
+        let Ok(repo) = git2::Repository::open(&path) else {
+            return Err(PackageRepoError::MissingCheckout(path));
+        };
+        let oid = git2::Oid::from_str(&pin.state.revision)?;
+
+        if repo.find_commit(oid).is_err() {
+            info!("Fetching {} to find {}", pin.identity, pin.state.revision);
+            // Fetch from the raw remote url, the configured one is redirected
+            // to this checkout by our own insteadOf.
+            let repo_url = remote_url(&repo).unwrap_or_else(|| pin.location.clone());
+            let remote = repo.remote_anonymous(&repo_url)?;
+            self.fetch(
+                &repo,
+                &remote,
+                &["refs/heads/*:refs/heads/*", "refs/tags/*:refs/tags/*"],
+            )?;
+            if repo.find_commit(oid).is_err() {
+                return Ok(SignatureStatus::Missing);
+            }
+        }
+
+        signature::verify_commit(&repo, oid, keyring)
+    }
+
+    fn clone_repo(
+        &self,
+        repo_url: &str,
+        path: &path::Path,
+        mirror: Option<&path::Path>,
+    ) -> Result<git2::Repository, PackageRepoError> {
+        self.retrying(repo_url, |attempt| {
+            // A failed attempt can leave a half-written checkout behind.
+            if attempt > 0 && path.exists() {
+                info!("Removing {} before retrying", path.display());
+                std::fs::remove_dir_all(path)?;
+            }
+            let (repo_url, path) = (repo_url.to_string(), path.to_path_buf());
+            let mirror = mirror.map(path::Path::to_path_buf);
+            self.bounded(move |transfer| transfer.clone_repo(&repo_url, &path, mirror.as_deref()))
+        })
+    }
+
+    /// Updates the remote-tracking branch of a pin's branch, forced so a
+    /// rewritten branch is followed too. Bare clones keep the branch as
+    /// their own.
+    fn branch_refspec(&self, bare: bool, pin: &v2::Pin) -> Option<String> {
+        let branch = pin.state.branch.as_deref()?;
+        Some(if bare {
+            format!("+refs/heads/{}:refs/heads/{}", branch, branch)
+        } else {
+            format!(
+                "+refs/heads/{}:refs/remotes/{}/{}",
+                branch,
+                self.remote_name(),
+                branch
+            )
+        })
+    }
+
+    /// Pins the tip of a followed branch, for pins that name a branch but
+    /// no revision. Other pins are returned as they are.
+    fn follow_branch(
+        &self,
+        repo: &git2::Repository,
+        pin: &v2::Pin,
+    ) -> Result<v2::Pin, PackageRepoError> {
+        let Some(branch) = pin.state.followed_branch() else {
+            return Ok(pin.clone());
+        };
+        let tracking = if repo.is_bare() {
+            format!("refs/heads/{}", branch)
+        } else {
+            format!("refs/remotes/{}/{}", self.remote_name(), branch)
+        };
+        let tip = repo
+            .revparse_single(&tracking)
+            .and_then(|tip| tip.peel_to_commit())
+            .map_err(|_| PackageRepoError::BranchNotFound {
+                identity: pin.identity.clone(),
+                branch: branch.to_string(),
+            })?;
+        info!("Following {} of {} at {}", branch, pin.identity, tip.id());
+        let mut followed = pin.clone();
+        followed.state.revision = tip.id().to_string();
+        Ok(followed)
+    }
+
+    /// Followed branches are always checked out on their local branch, so
+    /// the checkout keeps tracking them.
+    fn checkout_by(&self, pin: &v2::Pin) -> CheckoutBy {
+        if pin.state.followed_branch().is_some() {
+            CheckoutBy::Branch
+        } else {
+            self.options.checkout_by
+        }
+    }
+
+    /// An existing checkout that already holds the pinned revision is only
+    /// fetched with `--force-fetch`, or with `--refresh-older-than` once its
+    /// last fetch is that old. Returns why the fetch is skipped.
+    fn reason_to_skip_fetch(&self, repo: &git2::Repository, pin: &v2::Pin) -> Option<String> {
+        let has_revision = repo
+            .revparse_single(&pin.state.revision)
+            .and_then(|object| object.peel_to_commit())
+            .is_ok();
+        if self.options.force_fetch || !has_revision {
+            return None;
+        }
+
+        let Some(max_age) = self.options.refresh_older_than else {
+            return Some(format!(
+                "{} already has {}, not fetching",
+                pin.identity, pin.state.revision
+            ));
+        };
+        last_fetched(repo)
+            .and_then(|time| time.elapsed().ok())
+            .filter(|age| *age < max_age)
+            .map(|age| {
+                format!(
+                    "{} was fetched {}s ago, not refreshing",
+                    pin.identity,
+                    age.as_secs()
+                )
+            })
+    }
+
+    /// With `--touch`, updates the modification time of a reused checkout so
+    /// external cache eviction sees it as recently used.
+    fn touch(&self, path: &path::Path) -> Result<(), PackageRepoError> {
+        if self.options.touch {
+            filetime::set_file_mtime(path, filetime::FileTime::now())?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the URL an existing checkout was cloned from unless
+    /// `rewrite_existing_remotes` is set, so a change in the preferred
+    /// protocol doesn't churn remotes that already work.
+    fn reconcile_remote_url(
+        &self,
+        repo: &git2::Repository,
+        repo_url: &str,
+    ) -> Result<(), PackageRepoError> {
+        let key = format!("remote.{}.url", self.remote_name());
+        let Ok(existing_url) = repo.config()?.get_string(&key) else {
+            return Ok(());
+        };
+        if existing_url == repo_url {
+            return Ok(());
+        }
+
+        if self.options.rewrite_existing_remotes {
+            info!(
+                "Changing remote url from {} to {}",
+                url::redact(&existing_url),
+                url::redact(repo_url)
+            );
+            repo.remote_set_url(self.remote_name(), repo_url)?;
+            return Ok(());
+        }
+
+        let (existing, preferred) = (url::protocol(&existing_url), url::protocol(repo_url));
+        if existing != preferred {
+            info!(
+                "{} was cloned over {}, fetching over it instead of {}. Use --rewrite-existing-remotes to switch",
+                url::redact(&existing_url),
+                existing,
+                preferred
+            );
+        }
+        Ok(())
+    }
+
+    /// Checkouts in a content-addressed store are always bare.
+    fn is_bare(&self) -> bool {
+        self.cas_dir.is_some() || self.options.bare
+    }
+
+    fn remote_name(&self) -> &str {
+        self.options.remote_name.as_deref().unwrap_or(DEFAULT_REMOTE)
+    }
+
+    /// Fetches `refspecs` from `remote`, which is looked up again by name,
+    /// or by url when it is anonymous, on the thread doing the transfer.
+    fn fetch(
+        &self,
+        repo: &git2::Repository,
+        remote: &git2::Remote,
+        refspecs: &[&str],
+    ) -> Result<(), PackageRepoError> {
+        let repo_url = remote.url().unwrap_or_default().to_string();
+        self.retrying(&repo_url, |_| {
+            let path = repo.path().to_path_buf();
+            let name = remote.name().map(String::from);
+            let repo_url = repo_url.clone();
+            let refspecs: Vec<String> =
+                refspecs.iter().map(|refspec| refspec.to_string()).collect();
+            self.bounded(move |transfer| {
+                let repo = git2::Repository::open(&path)?;
+                let mut remote = match &name {
+                    Some(name) => repo.find_remote(name)?,
+                    None => repo.remote_anonymous(&repo_url)?,
+                };
+                let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+                transfer.fetch(&repo, &mut remote, &refspecs)
+            })
+        })
+    }
+
+    /// Runs `operation` again after an error that looks transient, up to
+    /// `--retries` times, doubling the wait before each attempt. It is given
+    /// the number of the attempt, starting at 0.
+    fn retrying<T>(
+        &self,
+        repo_url: &str,
+        mut operation: impl FnMut(u32) -> Result<T, PackageRepoError>,
+    ) -> Result<T, PackageRepoError> {
+        let mut attempt = 0;
+        loop {
+            match operation(attempt) {
+                Err(error)
+                    if attempt < self.options.retries
+                        && error.is_transient()
+                        && !self.deadline_passed() =>
+                {
+                    let wait = Duration::from_secs(1 << attempt.min(6));
+                    attempt += 1;
+                    warn!(
+                        "{} failed: {}. Retrying in {}s ({}/{})",
+                        url::redact(repo_url),
+                        error,
+                        wait.as_secs(),
+                        attempt,
+                        self.options.retries
+                    );
+                    std::thread::sleep(wait);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Makes sure the pinned revision is in `repo` after branches were
+    /// fetched. Commits that are on no branch are looked for in the tags
+    /// next, and as a last resort fetched by id, which only some servers
+    /// allow. Fails when the revision still can't be found, rather than
+    /// leaving the checkout at another commit.
+    fn fetch_missing_revision(
+        &self,
+        repo: &git2::Repository,
+        pin: &v2::Pin,
+    ) -> Result<(), PackageRepoError> {
+        let present = || {
+            repo.revparse_single(&pin.state.revision)
+                .and_then(|object| object.peel_to_commit())
+                .is_ok()
+        };
+        if present() {
+            return Ok(());
+        }
+
+        let remote = repo.find_remote(self.remote_name())?;
+        info!(
+            "{} is not on any branch of {}, fetching tags",
+            pin.state.revision, pin.identity
+        );
+        self.fetch(repo, &remote, &["+refs/tags/*:refs/tags/*"])?;
+        if present() {
+            return Ok(());
+        }
+
+        info!(
+            "{} is not on any tag of {}, fetching it directly",
+            pin.state.revision, pin.identity
+        );
+        match self.fetch(repo, &remote, &[&pin.state.revision]) {
+            Err(error @ PackageRepoError::TimedOut(_)) => return Err(error),
+            Err(err) => info!("Fetching {} directly failed: {}", pin.state.revision, err),
+            Ok(()) => {}
+        }
+        if present() {
+            return Ok(());
+        }
+
+        log::error!(
+            "Revision {} of {} was not found on any branch or tag and the server refused to send it",
+            pin.state.revision, pin.identity
+        );
+        Err(PackageRepoError::RevisionNotFound {
+            identity: pin.identity.clone(),
+            revision: pin.state.revision.clone(),
+        })
+    }
+
+    fn authenticator(&self) -> GitAuthenticator {
+        self.git.lock().unwrap().clone()
+    }
+
+    /// Runs a clone or fetch, on its own thread when `--timeout` is set so
+    /// it can be given up on once the remote has sent nothing for that long.
+    fn bounded<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&Transfer) -> Result<T, git2::Error> + Send + 'static,
+    ) -> Result<T, PackageRepoError> {
+        // Packages are installed on threads named after them.
+        let identity = std::thread::current().name().unwrap_or_default().to_string();
+        let progress = TransferProgress::new(identity, self.status_line.clone());
+        let transfer = Transfer {
+            progress: progress.clone(),
+            git: self.authenticator(),
+            http_proxy: self.options.http_proxy.clone(),
+            git_protocol: self.options.git_protocol,
+            depth: self.options.depth,
+            deadline: self.deadline,
+            bare: self.is_bare(),
+            remote_name: self.remote_name().to_string(),
+        };
+        let Some(timeout) = self.options.timeout else {
+            return Ok(operation(&transfer)?);
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        // The thread is detached on timeout; libgit2 offers no way to cancel a
+        // transfer that is stuck waiting on the server. It keeps the package's
+        // name so its log lines can still be told apart.
+        let mut builder = std::thread::Builder::new();
+        if let Some(name) = std::thread::current().name() {
+            builder = builder.name(name.to_string());
+        }
+        builder.spawn(move || {
+            let _ = sender.send(operation(&transfer));
+        })?;
+
+        loop {
+            match receiver.recv_timeout(timeout.saturating_sub(progress.idle())) {
+                Ok(result) => return Ok(result?),
+                Err(mpsc::RecvTimeoutError::Timeout) if progress.idle() >= timeout => {
+                    return Err(PackageRepoError::TimedOut(timeout))
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    panic!("clone or fetch thread panicked")
+                }
+            }
+        }
+    }
+
+    /// Keeps the pins whose identity matches one of the `--filter` patterns,
+    /// or every pin when there are none, and then drops the ones matching an
+    /// `--exclude` pattern.
+    fn filter_pins(pins: Vec<v2::Pin>, options: &InstallOptions) -> Vec<v2::Pin> {
+        for exclude in &options.excludes {
+            if !pins.iter().any(|pin| exclude.matches(&pin.identity)) {
+                warn!("--exclude {} does not match any package", exclude);
+            }
+        }
+
+        let total = pins.len();
+        let pins: Vec<v2::Pin> = pins
+            .into_iter()
+            .filter(|pin| Self::matches_filters(pin, options))
+            .collect();
+        if !options.filters.is_empty() {
+            info!(
+                "Installing {} of {} package(s), {} skipped by --filter",
+                pins.len(),
+                total,
+                total - pins.len()
+            );
+        }
+
+        pins.into_iter()
+            .filter(|pin| {
+                let excluded = Self::matches_excludes(pin, options);
+                if excluded {
+                    info!("Excluding {}", pin.identity);
+                }
+                !excluded
+            })
+            .collect()
+    }
+
+    fn is_selected(pin: &v2::Pin, options: &InstallOptions) -> bool {
+        Self::matches_filters(pin, options) && !Self::matches_excludes(pin, options)
+    }
+
+    fn matches_filters(pin: &v2::Pin, options: &InstallOptions) -> bool {
+        options.filters.is_empty()
+            || options
+                .filters
+                .iter()
+                .any(|filter| filter.matches(&pin.identity))
+    }
+
+    fn matches_excludes(pin: &v2::Pin, options: &InstallOptions) -> bool {
+        options
+            .excludes
+            .iter()
+            .any(|exclude| exclude.matches(&pin.identity))
+    }
+
+    /// Reports every pin with an insecure URL, naming the file it came from,
+    /// and either fails or drops those pins depending on `policy`.
+    fn require_https(
+        files: &mut [(path::PathBuf, v2::Resolved)],
+        policy: InsecurePolicy,
+        format: DiagnosticsFormat,
+    ) -> Result<(), PackageRepoError> {
+        let mut insecure = 0;
+        for (file, resolved) in files.iter_mut() {
+            resolved.pins.retain(|pin| {
+                if !resolved::is_insecure(pin) {
+                    return true;
+                }
+                insecure += 1;
+                let (severity, message) = match policy {
+                    InsecurePolicy::Reject => (
+                        Severity::Error,
+                        format!("{} uses an insecure url: {}", pin.identity, pin.location),
+                    ),
+                    InsecurePolicy::Skip => (
+                        Severity::Warning,
+                        format!(
+                            "Skipping {} as it uses an insecure url: {}",
+                            pin.identity, pin.location
+                        ),
+                    ),
+                };
+                Diagnostic::for_pin(severity, "insecure-url", message, file, pin).emit(format);
+                false
+            });
+        }
+
+        if insecure > 0 && policy == InsecurePolicy::Reject {
+            return Err(PackageRepoError::InsecureUrl(insecure));
+        }
+        Ok(())
+    }
+
+    /// Applies the lockfile next to the scanned path. Locked revisions win over
+    /// resolved ones. Unless `write` is false, the lockfile is created from the
+    /// resolved pins when it doesn't exist yet or when `update` is set, and
+    /// otherwise gains new packages and loses removed ones. Also returns the
+    /// lockfile as it was before this run.
+    fn locked_pins(
+        path: &path::Path,
+        pins: Vec<v2::Pin>,
+        update: bool,
+        write: bool,
+    ) -> Result<(Vec<v2::Pin>, Option<v2::Resolved>), PackageRepoError> {
+        let dir = if path.is_dir() {
+            path
+        } else {
+            path.parent().unwrap_or(path)
+        };
+
+        let previous = lock::read(dir)?;
+        let pins = match &previous {
+            Some(locked) if !update => {
+                let pins = lock::apply(pins, locked);
+                // Packages added or removed since are recorded, with every
+                // locked revision kept, so they are only reported once.
+                if write && !lock::changes(locked, &pins).is_empty() {
+                    lock::write(dir, &pins)?;
+                }
+                pins
+            }
+            _ if !write => pins,
+            _ => {
+                if previous.is_none() {
+                    warn!(
+                        "Creating {}. Later runs install its revisions instead of the resolved ones until --update-lock is given",
+                        dir.join(lock::LOCK_FILE).display()
+                    );
+                }
+                lock::write(dir, &pins)?;
+                pins
+            }
+        };
+        Ok((pins, previous))
+    }
+
+    /// The pins under `path` as `install` would use them, with the revisions
+    /// of the lockfile next to it applied. The lockfile is never written.
+    fn installed_pins(path: &path::Path) -> Result<Vec<v2::Pin>, PackageRepoError> {
+        let (pins, _) = Self::locked_pins(path, parse_all_recursive(path)?, false, false)?;
+        Ok(pins)
+    }
+
+    /// Says which https remotes are authenticated with `token`, and which
+    /// hosts it isn't used for because their locations are cloned over ssh.
+    fn report_token(token: &Token, pins: &[v2::Pin], url_rewriter: &UrlRewriter) {
+        let hosts = if token.host == "*" {
+            "every host"
+        } else {
+            &token.host
+        };
+        info!(
+            "Authenticating https remotes on {} with the token in {}",
+            hosts, token.var
+        );
+
+        let mut over_ssh: Vec<String> = pins
+            .iter()
+            .filter(|pin| url::protocol(&pin.location) == "https")
+            .filter(|pin| url::protocol(&url_rewriter.rewrite(pin)) == "ssh")
+            .filter_map(|pin| url::host(&pin.location))
+            .filter(|host| token.applies_to(host))
+            .collect();
+        over_ssh.sort();
+        over_ssh.dedup();
+        if !over_ssh.is_empty() {
+            info!(
+                "{} isn't used for {}, as https locations there are cloned over ssh. Pass --no-ssh to clone them over https",
+                token.var,
+                over_ssh.join(", ")
+            );
+        }
+    }
+
+    /// Prints the pins that changed since the previous lockfile, and writes
+    /// them to the changed report if one was requested.
+    fn report_changes(&self, changes: &[lock::Change]) -> Result<(), PackageRepoError> {
+        // stdout is reserved for the config fragment or JSON events in those
+        // modes.
+        let to_stdout = self.options.format == OutputFormat::Text && !self.options.print_config;
+        for change in changes {
+            if to_stdout {
+                println!("{}", change);
+            } else {
+                info!("{}", change);
+            }
+        }
+
+        if let Some(report) = &self.options.changed_report {
+            let contents: String = changes
+                .iter()
+                .map(|change| format!("{}\n", change))
+                .collect();
+            std::fs::write(report, contents)?;
+        }
+        Ok(())
+    }
+
+    fn check_free_space(&self) -> Result<(), PackageRepoError> {
+        let Some(required) = self.options.min_free_space else {
+            return Ok(());
+        };
+
+        let available = fs2::available_space(self.checkouts_dir())?;
+        if available < required {
+            return Err(PackageRepoError::LowDiskSpace {
+                available,
+                required,
+            });
+        }
+        Ok(())
+    }
+
+    fn deadline_passed(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn checkout_path(&self, pin: &v2::Pin) -> path::PathBuf {
+        if let Some(cas_dir) = &self.cas_dir {
+            return cas::path(cas_dir, &pin.location);
+        }
+
+        let path = self.checkouts_dir().join(&pin.identity);
+        if !cfg!(windows) || path.as_os_str().len() <= MAX_WINDOWS_CHECKOUT_PATH {
+            return path;
+        }
+
+        let short = self.checkouts_dir().join(short_checkout_name(&pin.identity));
+        warn!(
+            "{} would exceed the Windows path limit, using {} instead. Use a shorter REPO_DIR to avoid this",
+            path.display(),
+            short.display()
+        );
+        short
+    }
+
+    /// Where checkouts are cloned when no content-addressed store is used.
+    pub fn checkouts_dir(&self) -> path::PathBuf {
+        self.dir.join(path::Path::new(CHECKOUTS_DIR))
+    }
+
+    /// Redirects `repo_url` to `proxy_path` and marks the value as ours.
+    /// Values someone else set for the same path are kept and ours is added
+    /// next to them, unless `--force` is given, which replaces them.
+    fn set_global_git_proxy(
+        &self,
+        repo_url: &str,
+        proxy_path: &str,
+    ) -> Result<(), PackageRepoError> {
+        let existing = self.proxy_config()?;
+        let managed = git_config::values(&existing, &git_config::managed_key(proxy_path));
+        let mut config = self.config.lock().unwrap();
+        for variable in self.options.key_style.variables() {
+            let key = git_config::proxy_key_with(proxy_path, variable);
+            let values = git_config::values(&existing, &key);
+            let foreign: Vec<&str> = values
+                .iter()
+                .filter(|value| !managed.contains(value) && *value != repo_url)
+                .map(String::as_str)
+                .collect();
+            if foreign.is_empty() || self.options.force {
+                if !foreign.is_empty() {
+                    warn!(
+                        "Replacing {} = {}, which spm-git-swap didn't write, as --force was given",
+                        key,
+                        foreign.join(", ")
+                    );
+                }
+                config.set(&key, repo_url)?;
+                continue;
+            }
+
+            warn!(
+                "{} is also set to {}, which spm-git-swap didn't write. Keeping it next to {}, use --force to replace it",
+                key,
+                foreign.join(", "),
+                repo_url
+            );
+            for value in values.iter().filter(|value| managed.contains(value)) {
+                config.remove_value(&key, value)?;
+            }
+            config.add(&key, repo_url)?;
+        }
+        config.set(&git_config::managed_key(proxy_path), repo_url)?;
+        if !git_config::marks_values(&existing) {
+            config.set(git_config::MARKS_VALUES, "true")?;
+        }
+        Ok(())
+    }
+
+    /// Install applies its git config changes at the end of the run, so until
+    /// then our own insteadOf still redirects the url of the checkout at
+    /// `proxy_path` to the checkout itself, and libgit2 would clone or fetch
+    /// from there. The redirect [`Self::remove_global_git_proxy`] staged for
+    /// removal is taken out right away instead.
+    fn lift_own_redirect(&self, proxy_path: &str) -> Result<(), PackageRepoError> {
+        let existing = self.proxy_config()?;
+        let managed = git_config::values(&existing, &git_config::managed_key(proxy_path));
+        let legacy = !git_config::marks_values(&existing);
+        let key = git_config::proxy_key_with(proxy_path, git_config::INSTEAD_OF);
+        let mut config = self.config.lock().unwrap();
+        for value in git_config::values(&existing, &key) {
+            if legacy || managed.contains(&value) {
+                config.lift(&key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the values this tool wrote for `proxy_path`. Entries written
+    /// before values were marked, with no marker at all, are removed whole.
+    fn remove_global_git_proxy(&self, proxy_path: &str) -> Result<(), PackageRepoError> {
+        let existing = self.proxy_config()?;
+        let managed_key = git_config::managed_key(proxy_path);
+        let managed = git_config::values(&existing, &managed_key);
+        let legacy = !git_config::marks_values(&existing);
+        let mut config = self.config.lock().unwrap();
+        // Remove every style so switching styles doesn't leave stale keys.
+        for variable in KeyStyle::Both.variables() {
+            let key = git_config::proxy_key_with(proxy_path, variable);
+            if legacy {
+                config.remove(&key)?;
+                continue;
+            }
+            for value in git_config::values(&existing, &key) {
+                if managed.contains(&value) {
+                    config.remove_value(&key, &value)?;
+                }
+            }
+        }
+        config.remove(&managed_key)?;
+        Ok(())
+    }
+}
+
+fn describe_checkout(
+    identity: String,
+    path: path::PathBuf,
+    proxies: &[git_config::ProxyEntry],
+) -> manifest::Checkout {
+    let repo = git2::Repository::open(&path).ok();
+    let location = repo.as_ref().and_then(remote_url);
+    let head = repo.as_ref().and_then(|repo| {
+        repo.head()
+            .ok()
+            .and_then(|head| head.target())
+            .map(|oid| oid.to_string())
+    });
+    let proxy = proxies
+        .iter()
+        .find(|proxy| proxy.path == path)
+        .map(|proxy| proxy.url.clone());
+
+    manifest::Checkout {
+        identity,
+        path,
+        location,
+        head,
+        proxy,
+        revision: None,
+        version: None,
+    }
+}
+
+/// Checks that the checkout a proxy entry redirects to is usable.
+fn proxy_health(proxy: &git_config::ProxyEntry) -> ProxyHealth {
+    if !proxy.path.exists() {
+        return ProxyHealth::Missing;
+    }
+    let Ok(repo) = git2::Repository::open(&proxy.path) else {
+        return ProxyHealth::NotARepository;
+    };
+    if repo.head().and_then(|head| head.peel_to_commit()).is_err() {
+        return ProxyHealth::NoHead;
+    }
+    match remote_url(&repo) {
+        Some(remote) if url::identity(&remote) != url::identity(&proxy.url) => {
+            ProxyHealth::Mismatch
+        }
+        _ => ProxyHealth::Ok,
+    }
+}
+
+/// Tries a token from the environment, the credential helper and password
+/// prompts for https remotes. For ssh remotes, `ssh_key` alone is offered
+/// when given. Otherwise the agent is tried, when `SSH_AUTH_SOCK` is set, and
+/// then the default keys in `~/.ssh`.
+// Offering other keys next to an explicit one would let the server accept
+// whichever it knows first, which may lack access to the repository.
+fn authenticator(ssh_key: Option<path::PathBuf>) -> GitAuthenticator {
+    let mut git = GitAuthenticator::new_empty()
+        .try_cred_helper(true)
+        .try_password_prompt(3)
+        .add_default_username()
+        .prompt_ssh_key_password(true);
+    if let Some(token) = Token::from_env() {
+        git = git.add_plaintext_credentials(token.host, token.username, token.token);
+    }
+    match ssh_key {
+        Some(ssh_key) => {
+            if !ssh_key.is_file() {
+                warn!("ssh key {} does not exist", ssh_key.display());
+            }
+            git.add_ssh_key_from_file(ssh_key, None)
+        }
+        None => git
+            .try_ssh_agent(std::env::var_os("SSH_AUTH_SOCK").is_some())
+            .add_default_ssh_keys(),
+    }
+}
+
+/// The identity file an ssh command line passes with `-i`, as in
+/// `GIT_SSH_COMMAND="ssh -i ~/.ssh/deploy_key"`.
+fn ssh_key_from_command(command: &str) -> Option<path::PathBuf> {
+    let mut args = command.split_whitespace();
+    let key = loop {
+        let arg = args.next()?;
+        if arg == "-i" {
+            break args.next()?;
+        }
+        if let Some(key) = arg.strip_prefix("-i") {
+            break key;
+        }
+    };
+    let key = key.trim_matches(['\'', '"']);
+    match key.strip_prefix("~/") {
+        Some(rest) => std::env::var_os("HOME").map(|home| path::Path::new(&home).join(rest)),
+        None => Some(path::PathBuf::from(key)),
+    }
+}
+
+fn head_commit(repo: &git2::Repository) -> Option<String> {
+    repo.head()
+        .and_then(|head| head.peel_to_commit())
+        .map(|commit| commit.id().to_string())
+        .ok()
+}
+
+/// Returns the URL of the checkout's `origin` remote, or of its only remote
+/// when it was cloned with a different remote name. The raw config value is
+/// read because `Remote::url` applies our own insteadOf rewrites and would
+/// report the checkout itself.
+fn remote_url(repo: &git2::Repository) -> Option<String> {
+    let config = repo.config().ok()?;
+    let remotes = repo.remotes().ok()?;
+    let name = if remotes.iter().flatten().any(|name| name == DEFAULT_REMOTE) {
+        DEFAULT_REMOTE.to_string()
+    } else {
+        remotes.iter().flatten().next()?.to_string()
+    };
+    config.get_string(&format!("remote.{}.url", name)).ok()
+}
+
+/// What a clone or fetch needs from the [`PackageRepo`], owned so it can run
+/// on a thread that is abandoned when the transfer times out.
+struct Transfer {
+    git: GitAuthenticator,
+    progress: TransferProgress,
+    http_proxy: Option<String>,
+    git_protocol: Option<u8>,
+    depth: Option<u32>,
+    deadline: Option<Instant>,
+    /// Clones into a content-addressed store are bare.
+    bare: bool,
+    remote_name: String,
+}
+
+impl Transfer {
+    fn deadline_passed(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn clone_repo(
+        &self,
+        repo_url: &str,
+        path: &path::Path,
+        mirror: Option<&path::Path>,
+    ) -> Result<git2::Repository, git2::Error> {
+        if let Some(mirror) = mirror {
+            return self.clone_with_mirror(repo_url, path, mirror);
+        }
+
+        let config = git_config::open_default()?;
+        let mut builder = git2::build::RepoBuilder::new();
+        builder.bare(self.bare);
+        let mut options = self.fetch_options(&config, repo_url);
+        if let Some(depth) = self.depth {
+            options.depth(i32::try_from(depth).unwrap_or(i32::MAX));
+        }
+        builder.fetch_options(options);
+        let remote_name = self.remote_name.clone();
+        let bare = self.bare;
+        // Bare clones keep the remote's branches as their own, so a clone of
+        // the clone sees them.
+        builder.remote_create(move |repo, _, url| {
+            if bare {
+                repo.remote_with_fetch(&remote_name, url, MIRROR_REFSPEC)
+            } else {
+                repo.remote(&remote_name, url)
+            }
+        });
+        builder.clone(repo_url, path)
+    }
+
+    /// Clones like `git clone --reference <mirror>`. libgit2's clone refuses
+    /// a repository that already has refs, so the steps are done by hand:
+    /// borrow the mirror's objects, fetch, then check out the remote's
+    /// default branch.
+    fn clone_with_mirror(
+        &self,
+        repo_url: &str,
+        path: &path::Path,
+        mirror: &path::Path,
+    ) -> Result<git2::Repository, git2::Error> {
+        let bare = self.bare;
+        let repo = if bare {
+            git2::Repository::init_bare(path)?
+        } else {
+            git2::Repository::init(path)?
+        };
+        reference_mirror(&repo, mirror)?;
+
+        let remote_name = &self.remote_name;
+        let refspec = if bare {
+            String::from(MIRROR_REFSPEC)
+        } else {
+            format!("+refs/heads/*:refs/remotes/{}/*", remote_name)
+        };
+        let default_branch = {
+            let mut remote = repo.remote(remote_name, repo_url)?;
+            self.fetch(&repo, &mut remote, &[&refspec])?;
+            remote
+                .default_branch()
+                .ok()
+                .and_then(|branch| branch.as_str().map(String::from))
+        };
+        for reference in repo.references_glob(&format!("{}*", MIRROR_REFS))? {
+            reference?.delete()?;
+        }
+
+        let Some(head) = default_branch else {
+            return Ok(repo);
+        };
+        if !bare {
+            let branch = head.trim_start_matches("refs/heads/");
+            let upstream = format!("{}/{}", remote_name, branch);
+            let target = repo
+                .find_branch(&upstream, git2::BranchType::Remote)?
+                .get()
+                .peel_to_commit()?;
+            repo.branch(branch, &target, true)?
+                .set_upstream(Some(&upstream))?;
+        }
+        repo.set_head(&head)?;
+        if !bare {
+            repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+        }
+        Ok(repo)
+    }
+
+    fn fetch(
+        &self,
+        repo: &git2::Repository,
+        remote: &mut git2::Remote,
+        refspecs: &[&str],
+    ) -> Result<(), git2::Error> {
+        let config = repo.config()?;
+        let repo_url = remote.url().unwrap_or_default().to_string();
+        let mut options = self.fetch_options(&config, &repo_url);
+        // Deepening is left to the pinned revision being fetched by id, a
+        // full checkout is never made shallow.
+        if let Some(depth) = self.depth.filter(|_| repo.is_shallow()) {
+            options.depth(i32::try_from(depth).unwrap_or(i32::MAX));
+        }
+        remote.fetch(refspecs, Some(&mut options), None)
+    }
+
+    fn fetch_options<'a>(
+        &'a self,
+        config: &'a git2::Config,
+        repo_url: &str,
+    ) -> git2::FetchOptions<'a> {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(self.git.credentials(config));
+        callbacks.sideband_progress(|_| {
+            self.progress.touch();
+            true
+        });
+        // Returning false from the progress callback aborts the transfer.
+        callbacks.transfer_progress(|stats| {
+            self.progress.update(&stats);
+            !self.deadline_passed()
+        });
+
+        let mut options = git2::FetchOptions::new();
+        options.remote_callbacks(callbacks);
+
+        if let Some(proxy) = proxy_options(self.http_proxy.as_deref()) {
+            options.proxy_options(proxy);
+        }
+
+        if let Some(version) = self.git_protocol {
+            if repo_url.starts_with("https://") || repo_url.starts_with("http://") {
+                options.custom_headers(&[&format!("Git-Protocol: version={}", version)]);
+            } else {
+                warn!(
+                    "Git protocol version {} can't be applied to {}, only http(s) transports support it",
+                    version,
+                    url::redact(repo_url)
+                );
+            }
+        }
+
+        options
+    }
+}
+
+/// Builds the libgit2 proxy settings for `http_proxy`, which is either a proxy
+/// url or `auto`.
+pub fn proxy_options(http_proxy: Option<&str>) -> Option<git2::ProxyOptions<'static>> {
+    let http_proxy = http_proxy?;
+    let mut options = git2::ProxyOptions::new();
+    if http_proxy == "auto" {
+        options.auto();
+    } else {
+        options.url(http_proxy);
+    }
+    Some(options)
+}
+
+/// Looks for a repository in SwiftPM's cache that belongs to `pin` and
+/// already contains its pinned revision. SwiftPM names these directories
+/// either `<identity>` or `<identity>-<hash>`.
+fn find_spm_checkout(cache: &path::Path, pin: &v2::Pin) -> Option<path::PathBuf> {
+    let identity = pin.identity.to_lowercase();
+    let mut candidates: Vec<path::PathBuf> = std::fs::read_dir(cache)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_lowercase();
+            name == identity || name.starts_with(&format!("{}-", identity))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    candidates.sort();
+
+    candidates.into_iter().find(|candidate| {
+        let Ok(repo) = git2::Repository::open(candidate) else {
+            return false;
+        };
+        let found = git2::Oid::from_str(&pin.state.revision)
+            .and_then(|oid| repo.find_commit(oid))
+            .is_ok();
+        if !found {
+            info!(
+                "SwiftPM checkout {} doesn't contain revision {}",
+                candidate.display(),
+                pin.state.revision
+            );
+        }
+        found
+    })
+}
+
+/// Lists the `Package.resolved` files under `root` that differ between
+/// `reference` and the working tree of the git repository containing `root`.
+/// Deleted files are left out.
+fn changed_resolved_files(
+    root: &path::Path,
+    reference: &str,
+) -> Result<Vec<path::PathBuf>, git2::Error> {
+    let repo = git2::Repository::discover(root)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("repository has no working tree"))?
+        .to_path_buf();
+    let canonicalize = |path: &path::Path| {
+        path.canonicalize()
+            .map_err(|err| git2::Error::from_str(&err.to_string()))
+    };
+    let (workdir, root) = (canonicalize(&workdir)?, canonicalize(root)?);
+    let tree = repo.revparse_single(reference)?.peel_to_tree()?;
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&tree), None)?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().map(|file| workdir.join(file)))
+        .filter(|file| {
+            file.file_name().is_some_and(|name| name == "Package.resolved")
+                && file.starts_with(&root)
+                && file.exists()
+        })
+        .collect())
+}
+
+/// Builds a command that runs `command` through the platform shell.
+fn shell(command: &str) -> std::process::Command {
+    if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// A short, stable directory name for `identity`: a readable prefix followed
+/// by an FNV-1a hash of the whole identity.
+fn short_checkout_name(identity: &str) -> String {
+    let hash = identity.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    let prefix: String = identity.chars().take(16).collect();
+    format!("{}-{:016x}", prefix, hash)
+}
+
+/// Borrows the objects of the repository at `mirror` through
+/// `objects/info/alternates`, and points temporary refs at the mirror's
+/// branches and tags so the fetch only asks the server for what the mirror
+/// doesn't already have.
+fn reference_mirror(repo: &git2::Repository, mirror: &path::Path) -> Result<(), git2::Error> {
+    let mirror = git2::Repository::open(mirror)?;
+    let objects = mirror.path().join("objects");
+    let objects = objects.canonicalize().unwrap_or(objects);
+
+    std::fs::write(
+        repo.path().join("objects").join("info").join("alternates"),
+        format!("{}\n", objects.display()),
+    )
+    .map_err(|err| git2::Error::from_str(&format!("failed to write alternates: {}", err)))?;
+    // The object database was opened before the alternates file existed.
+    repo.odb()?.add_disk_alternate(&objects.display().to_string())?;
+
+    for reference in mirror.references()? {
+        let reference = reference?;
+        let (Some(name), Some(target)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        if let Some(rest) = name
+            .strip_prefix("refs/heads/")
+            .or_else(|| name.strip_prefix("refs/tags/"))
+        {
+            repo.reference(
+                &format!("{}{}", MIRROR_REFS, rest),
+                target,
+                true,
+                "borrow objects from host mirror",
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// When `repo` was last cloned or fetched by us, if ever. This is the
+/// marker's own mtime, never the checkout directory's, which `--touch` moves
+/// forward without fetching anything.
+fn last_fetched(repo: &git2::Repository) -> Option<std::time::SystemTime> {
+    std::fs::metadata(repo.path().join(FETCHED_MARKER))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn mark_fetched(repo: &git2::Repository) -> Result<(), PackageRepoError> {
+    let marker = repo.path().join(FETCHED_MARKER);
+    std::fs::write(&marker, b"")?;
+    filetime::set_file_mtime(&marker, filetime::FileTime::now())?;
+    Ok(())
+}
+
+/// Whether `path` is a git repository whose HEAD can be read. A clone that
+/// died can leave a `.git` that opens but is unusable, such as one with a
+/// corrupted HEAD. A HEAD on a branch without commits yet is fine.
+fn is_usable_repo(path: &path::Path) -> bool {
+    git2::Repository::open(path).is_ok_and(|repo| match repo.head() {
+        Ok(_) => true,
+        Err(err) => err.code() == git2::ErrorCode::UnbornBranch,
+    })
+}
+
+/// The size of the files under `path`, without following symlinks.
+fn dir_size(path: &path::Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| dir_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Whether `path` is what a clone that died leaves behind: a directory whose
+/// `.git` is empty or too incomplete to read HEAD from. Nothing in it is
+/// worth keeping over a fresh clone.
+fn is_stale_clone(path: &path::Path) -> bool {
+    path.join(".git").is_dir() && !is_usable_repo(path)
+}
+
+/// Whether `path`, which isn't a usable checkout, holds something that only
+/// `--force` may delete to clone there: anything but an empty directory or a
+/// clone that died.
+fn is_in_the_way(path: &path::Path) -> Result<bool, PackageRepoError> {
+    Ok(path.exists() && !is_empty_dir(path)? && !is_stale_clone(path))
+}
+
+fn is_empty_dir(path: &path::Path) -> Result<bool, PackageRepoError> {
+    if !path.is_dir() {
+        return Ok(false);
+    }
+    Ok(std::fs::read_dir(path)?.next().is_none())
+}
+
+/// Renames `from` to `to`, falling back to copy and delete when they are on
+/// different filesystems.
+fn move_dir(from: &path::Path, to: &path::Path) -> Result<(), PackageRepoError> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    copy_dir(from, to)?;
+    std::fs::remove_dir_all(from)?;
+    Ok(())
+}
+
+fn copy_dir(from: &path::Path, to: &path::Path) -> Result<(), PackageRepoError> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            let link = std::fs::read_link(entry.path())?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(link, &target)?;
+            #[cfg(not(unix))]
+            std::fs::copy(entry.path().join(link), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, TempDir};
+
+    /// A repo in `dir` whose proxy entries are written to the config of a
+    /// throwaway repository instead of the global config.
+    fn package_repo(dir: &TempDir, options: InstallOptions) -> PackageRepo {
+        let config = dir.join("config");
+        git2::Repository::init(&config).unwrap();
+        let mut repo = PackageRepo::new(&dir.join("repo"))
+            .unwrap()
+            .with_local_config(Some(config));
+        repo.options = options;
+        repo
+    }
+
+    fn pin(identity: &str, location: &path::Path, revision: git2::Oid) -> v2::Pin {
+        v2::Pin {
+            identity: identity.to_string(),
+            kind: v2::Kind::RemoteSourceControl,
+            location: location.display().to_string(),
+            state: v2::State {
+                branch: None,
+                revision: revision.to_string(),
+                version: None,
+                checksum: None,
+            },
+        }
+    }
+
+    fn write_resolved(dir: &path::Path, pins: &[v2::Pin]) {
+        let resolved = v2::Resolved {
+            pins: pins.to_vec(),
+            version: 2,
+        };
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(
+            dir.join("Package.resolved"),
+            serde_json::to_string(&resolved).unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn head(path: &path::Path) -> Option<String> {
+        head_commit(&git2::Repository::open(path).unwrap())
+    }
+
+    /// The `insteadOf` values the proxy config of `repo` holds for `path`.
+    fn redirects(repo: &PackageRepo, path: &path::Path) -> Vec<String> {
+        let key = git_config::proxy_key_with(&path.display().to_string(), git_config::INSTEAD_OF);
+        git_config::values(&repo.proxy_config().unwrap(), &key)
+    }
+
+    fn set_mtime_ago(path: &path::Path, ago: Duration) {
+        let time = filetime::FileTime::from_system_time(std::time::SystemTime::now() - ago);
+        filetime::set_file_mtime(path, time).unwrap();
+    }
+
+    /// A checkout path holding a stray file instead of a repository.
+    fn not_a_repository(repo: &PackageRepo, pin: &v2::Pin) -> path::PathBuf {
+        let path = repo.checkouts_dir().join(&pin.identity);
+        std::fs::create_dir_all(path.join("Sources")).unwrap();
+        std::fs::write(path.join("Sources/Stray.swift"), "").unwrap();
+        path
+    }
+
+    #[test]
+    fn clone_refuses_to_replace_a_directory_that_is_not_a_repository() {
+        let dir = TempDir::new("not-a-repo");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = not_a_repository(&repo, &pin);
+
+        match repo.clone(&pin) {
+            Err(PackageRepoError::NotARepository(rejected)) => assert_eq!(rejected, path),
+            other => panic!("expected a non-repository error, got {:?}", other),
+        }
+        assert!(path.join("Sources/Stray.swift").exists());
+        assert!(!path.join(".git").exists());
+        assert!(redirects(&repo, &path).is_empty());
+    }
+
+    #[test]
+    fn clone_replaces_a_directory_that_is_not_a_repository_with_force() {
+        let dir = TempDir::new("not-a-repo-force");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                force: true,
+                ..Default::default()
+            },
+        );
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = not_a_repository(&repo, &pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        assert!(!path.join("Sources").exists());
+        assert_eq!(head(&path), Some(revisions[0].to_string()));
+        assert_eq!(redirects(&repo, &path), [pin.location]);
+    }
+
+    #[test]
+    fn clone_recovers_clones_that_died() {
+        let dir = TempDir::new("stale-clone");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+
+        let breakages: [fn(&path::Path); 2] = [
+            // Its `.git` is empty.
+            |path| {
+                std::fs::remove_dir_all(path.join(".git")).unwrap();
+                std::fs::create_dir(path.join(".git")).unwrap();
+            },
+            // Its HEAD was never written properly.
+            |path| std::fs::write(path.join(".git/HEAD"), "garbage").unwrap(),
+        ];
+        for breakage in breakages {
+            assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+            breakage(&path);
+            assert!(is_stale_clone(&path));
+
+            assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+            assert_eq!(head(&path), Some(revisions[0].to_string()));
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn checkouts_whose_git_dir_was_deleted_need_force() {
+        let dir = TempDir::new("deleted-git-dir");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        std::fs::remove_dir_all(path.join(".git")).unwrap();
+
+        assert!(matches!(
+            repo.clone(&pin),
+            Err(PackageRepoError::NotARepository(_))
+        ));
+        assert!(path.join("README").exists());
+    }
+
+    #[test]
+    fn offline_clones_leave_clones_that_died_in_place() {
+        let dir = TempDir::new("stale-clone-offline");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        std::fs::write(path.join(".git/HEAD"), "garbage").unwrap();
+
+        let offline = package_repo(
+            &dir,
+            InstallOptions {
+                offline: true,
+                force: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            offline.clone(&pin),
+            Err(PackageRepoError::MissingCheckout(_))
+        ));
+        assert!(path.join("README").exists());
+        assert!(path.join(".git").exists());
+    }
+
+    #[test]
+    fn local_pins_are_redirected_to_their_repository() {
+        let dir = TempDir::new("local-pin");
+        let revisions = test_support::init_repo(&dir.join("local"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("local", &dir.join("local"), revisions[0]);
+        pin.kind = v2::Kind::LocalSourceControl;
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+        let local = dir.join("local").canonicalize().unwrap();
+        assert_eq!(redirects(&repo, &local), [pin.location]);
+    }
+
+    #[test]
+    fn local_pins_without_a_repository_are_skipped() {
+        let dir = TempDir::new("local-pin-missing");
+        std::fs::create_dir(dir.join("not-a-repo")).unwrap();
+        let repo = package_repo(&dir, InstallOptions::default());
+        for name in ["missing", "not-a-repo"] {
+            let mut pin = pin(name, &dir.join(name), git2::Oid::zero());
+            pin.kind = v2::Kind::LocalSourceControl;
+            assert_eq!(repo.clone(&pin).unwrap(), Action::Skipped);
+        }
+        let config = repo.proxy_config().unwrap();
+        assert!(git_config::proxy_entries(&config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bare_checkouts_are_recognised_as_existing() {
+        let dir = TempDir::new("bare");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                bare: true,
+                ..Default::default()
+            },
+        );
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        let checkout = git2::Repository::open(&path).unwrap();
+        assert!(checkout.is_bare());
+        assert!(!path.join(".git").exists());
+        assert!(checkout
+            .find_branch("main", git2::BranchType::Local)
+            .is_ok());
+        assert!(is_usable_repo(&path));
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        pin.state.revision = test_support::commit(&upstream, "two").to_string();
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+        assert_eq!(head(&path), Some(pin.state.revision.clone()));
+    }
+
+    #[test]
+    fn branch_pins_follow_the_branch() {
+        let dir = TempDir::new("branch-pin");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        upstream
+            .branch("dev", &upstream.find_commit(revisions[0]).unwrap(), false)
+            .unwrap();
+        upstream.set_head("refs/heads/dev").unwrap();
+        let dev = test_support::commit(&upstream, "dev");
+        upstream.set_head("refs/heads/main").unwrap();
+        let repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        pin.state.revision = String::new();
+        pin.state.branch = Some(String::from("dev"));
+        let path = repo.checkout_path(&pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        assert_eq!(head(&path), Some(dev.to_string()));
+        let checkout = git2::Repository::open(&path).unwrap();
+        assert_eq!(checkout.head().unwrap().name(), Some("refs/heads/dev"));
+        let local = checkout
+            .find_branch("dev", git2::BranchType::Local)
+            .unwrap();
+        assert_eq!(
+            local.upstream().unwrap().name().unwrap(),
+            Some("origin/dev")
+        );
+
+        upstream.set_head("refs/heads/dev").unwrap();
+        let moved = test_support::commit(&upstream, "dev moved");
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+        assert_eq!(head(&path), Some(moved.to_string()));
+    }
+
+    #[test]
+    fn branch_pins_fail_for_missing_branches() {
+        let dir = TempDir::new("branch-pin-missing");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        pin.state.revision = String::new();
+        pin.state.branch = Some(String::from("gone"));
+
+        assert!(matches!(
+            repo.clone(&pin),
+            Err(PackageRepoError::BranchNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn lockfile_revisions_win_over_resolved_ones() {
+        let dir = TempDir::new("lockfile");
+        let locked = |identity: &str, digit: &str| {
+            pin(
+                identity,
+                &dir.join("upstream"),
+                git2::Oid::from_str(&digit.repeat(40)).unwrap(),
+            )
+        };
+        let revisions = |pins: &[v2::Pin]| {
+            pins.iter()
+                .map(|pin| format!("{}@{}", pin.identity, &pin.state.revision[..1]))
+                .collect::<Vec<_>>()
+        };
+
+        // Without a lockfile the resolved pins are used and recorded.
+        let (pins, previous) = PackageRepo::locked_pins(
+            dir.path(),
+            vec![locked("a", "1"), locked("b", "1")],
+            false,
+            true,
+        )
+        .unwrap();
+        assert!(previous.is_none());
+        assert_eq!(revisions(&pins), ["a@1", "b@1"]);
+        assert_eq!(
+            revisions(&lock::read(dir.path()).unwrap().unwrap().pins),
+            ["a@1", "b@1"]
+        );
+
+        // With one, its revisions win and only new identities are resolved.
+        let resolved = vec![locked("a", "2"), locked("b", "2"), locked("c", "2")];
+        let (pins, previous) = PackageRepo::locked_pins(dir.path(), resolved, false, true).unwrap();
+        assert!(previous.is_some());
+        assert_eq!(revisions(&pins), ["a@1", "b@1", "c@2"]);
+
+        // New identities are recorded and removed ones dropped, while locked
+        // revisions stay, so the next run reports no changes.
+        assert_eq!(
+            revisions(&lock::read(dir.path()).unwrap().unwrap().pins),
+            ["a@1", "b@1", "c@2"]
+        );
+        let resolved = vec![locked("a", "2"), locked("c", "3")];
+        let (pins, previous) = PackageRepo::locked_pins(dir.path(), resolved, false, true).unwrap();
+        assert_eq!(revisions(&pins), ["a@1", "c@2"]);
+        assert_eq!(lock::changes(&previous.unwrap(), &pins).len(), 1);
+        let (pins, previous) = PackageRepo::locked_pins(dir.path(), pins, false, true).unwrap();
+        assert!(lock::changes(&previous.unwrap(), &pins).is_empty());
+        assert_eq!(
+            revisions(&lock::read(dir.path()).unwrap().unwrap().pins),
+            ["a@1", "c@2"]
+        );
+
+        // Updating regenerates it from the resolved pins.
+        let (pins, _) = PackageRepo::locked_pins(
+            dir.path(),
+            vec![locked("a", "2"), locked("c", "2")],
+            true,
+            true,
+        )
+        .unwrap();
+        assert_eq!(revisions(&pins), ["a@2", "c@2"]);
+        assert_eq!(
+            revisions(&lock::read(dir.path()).unwrap().unwrap().pins),
+            ["a@2", "c@2"]
+        );
+    }
+
+    #[test]
+    fn lockfile_is_not_written_when_asked_not_to() {
+        let dir = TempDir::new("lockfile-readonly");
+        let pins = vec![pin("a", &dir.join("upstream"), git2::Oid::zero())];
+
+        let (pins, previous) = PackageRepo::locked_pins(dir.path(), pins, false, false).unwrap();
+        assert!(previous.is_none());
+        assert_eq!(pins.len(), 1);
+        assert!(!dir.join(lock::LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn clones_and_fetches_with_the_configured_remote_name() {
+        let dir = TempDir::new("remote-name");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                remote_name: Some(String::from("upstream")),
+                ..Default::default()
+            },
+        );
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        let checkout = git2::Repository::open(&path).unwrap();
+        let remotes = checkout.remotes().unwrap();
+        assert_eq!(remotes.iter().collect::<Vec<_>>(), [Some("upstream")]);
+
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        let two = test_support::commit(&upstream, "two");
+        pin.state.revision = two.to_string();
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+        assert!(checkout.find_commit(two).is_ok());
+    }
+
+    #[test]
+    fn replaced_pins_clone_the_fork_and_redirect_the_upstream() {
+        let dir = TempDir::new("replace");
+        let upstream = dir.join("upstream").display().to_string();
+        let fork = dir.join("fork").display().to_string();
+        let revisions = test_support::init_repo(&dir.join("fork"), &["forked"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                url_rewriter: UrlRewriter::default()
+                    .with_replacements(vec![(upstream.clone(), fork.clone())]),
+                ..Default::default()
+            },
+        );
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        assert_eq!(head(&path), Some(revisions[0].to_string()));
+        let checkout = git2::Repository::open(&path).unwrap();
+        let remote = checkout.find_remote("origin").unwrap();
+        assert_eq!(remote.url(), Some(fork.as_str()));
+        assert_eq!(redirects(&repo, &path), [upstream]);
+    }
+
+    #[test]
+    fn short_checkout_names_are_stable_and_distinct() {
+        let identity = "a-package-with-an-unreasonably-long-identity";
+        let short = short_checkout_name(identity);
+        assert_eq!(short, short_checkout_name(identity));
+        assert!(short.starts_with("a-package-with-a-"));
+        assert_eq!(short.len(), 16 + 1 + 16);
+        assert_ne!(
+            short,
+            short_checkout_name("a-package-with-an-unreasonably-long-identity-2")
+        );
+    }
+
+    #[test]
+    fn long_checkout_paths_are_shortened_on_windows() {
+        let dir = TempDir::new("long-path");
+        let repo = package_repo(&dir, InstallOptions::default());
+        let identity = "x".repeat(MAX_WINDOWS_CHECKOUT_PATH);
+        let pin = pin(&identity, &dir.join("upstream"), git2::Oid::zero());
+
+        let path = repo.checkout_path(&pin);
+        if cfg!(windows) {
+            assert_eq!(
+                path,
+                repo.checkouts_dir().join(short_checkout_name(&identity))
+            );
+            assert!(path.as_os_str().len() <= MAX_WINDOWS_CHECKOUT_PATH);
+        } else {
+            assert_eq!(path, repo.checkouts_dir().join(&identity));
+        }
+    }
+
+    fn insecure_files() -> Vec<(path::PathBuf, v2::Resolved)> {
+        let path = test_support::fixture("insecure.resolved");
+        let resolved = resolved::parse(&path).unwrap();
+        vec![(path, resolved)]
+    }
+
+    #[test]
+    fn require_https_rejects_insecure_urls() {
+        let mut files = insecure_files();
+
+        let result = PackageRepo::require_https(
+            &mut files,
+            InsecurePolicy::Reject,
+            DiagnosticsFormat::Human,
+        );
+        assert!(matches!(result, Err(PackageRepoError::InsecureUrl(1))));
+    }
+
+    #[test]
+    fn require_https_skips_insecure_urls_with_a_warning() {
+        let mut files = insecure_files();
+
+        PackageRepo::require_https(&mut files, InsecurePolicy::Skip, DiagnosticsFormat::Human)
+            .unwrap();
+        let identities: Vec<&str> = files[0]
+            .1
+            .pins
+            .iter()
+            .map(|pin| pin.identity.as_str())
+            .collect();
+        assert_eq!(identities, ["secure"]);
+    }
+
+    #[test]
+    fn fetches_revisions_only_reachable_through_a_tag() {
+        let dir = TempDir::new("tag-only");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let location = format!("file://{}", dir.join("upstream").display());
+        let checkout = git2::Repository::clone(&location, dir.join("checkout")).unwrap();
+
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = upstream.find_commit(revisions[0]).unwrap();
+        let hotfix = upstream
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "hotfix",
+                &parent.tree().unwrap(),
+                &[&parent],
+            )
+            .unwrap();
+        upstream
+            .tag_lightweight("1.0.1", &upstream.find_object(hotfix, None).unwrap(), false)
+            .unwrap();
+        let repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), hotfix);
+        pin.location = location;
+
+        assert!(checkout.find_commit(hotfix).is_err());
+        repo.fetch_missing_revision(&checkout, &pin).unwrap();
+        assert!(checkout.find_commit(hotfix).is_ok());
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        let cloned = git2::Repository::open(repo.checkout_path(&pin)).unwrap();
+        assert!(cloned.find_commit(hotfix).is_ok());
+    }
+
+    #[test]
+    fn revisions_the_remote_does_not_have_fail_the_package() {
+        let dir = TempDir::new("missing-revision");
+        test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let location = format!("file://{}", dir.join("upstream").display());
+        let checkout = git2::Repository::clone(&location, dir.join("checkout")).unwrap();
+        let repo = package_repo(&dir, InstallOptions::default());
+        let missing = git2::Oid::from_str(&"1".repeat(40)).unwrap();
+        let mut pin = pin("upstream", &dir.join("upstream"), missing);
+        pin.location = location;
+
+        match repo.fetch_missing_revision(&checkout, &pin) {
+            Err(PackageRepoError::RevisionNotFound { identity, revision }) => {
+                assert_eq!(identity, "upstream");
+                assert_eq!(revision, missing.to_string());
+            }
+            other => panic!("expected a missing revision, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refresh_older_than_fetches_from_the_threshold_on() {
+        let dir = TempDir::new("refresh-threshold");
+        let max_age = Duration::from_secs(60 * 60);
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                refresh_older_than: Some(max_age),
+                ..Default::default()
+            },
+        );
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        let marker = repo
+            .checkouts_dir()
+            .join(&pin.identity)
+            .join(".git")
+            .join(FETCHED_MARKER);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+
+        set_mtime_ago(&marker, max_age - Duration::from_secs(60));
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+
+        set_mtime_ago(&marker, max_age);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+
+        set_mtime_ago(&marker, max_age + Duration::from_secs(60));
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+    }
+
+    #[test]
+    fn touch_does_not_change_whether_a_checkout_is_refreshed() {
+        let dir = TempDir::new("refresh-touch");
+        let max_age = Duration::from_secs(60 * 60);
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                refresh_older_than: Some(max_age),
+                touch: true,
+                ..Default::default()
+            },
+        );
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        repo.clone(&pin).unwrap();
+        let path = repo.checkouts_dir().join(&pin.identity);
+        let marker = path.join(".git").join(FETCHED_MARKER);
+
+        // A stale fetch is refreshed even though the checkout was just touched.
+        set_mtime_ago(&marker, max_age + Duration::from_secs(60));
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+
+        // A recent fetch is reused however old the checkout directory looks.
+        set_mtime_ago(&marker, Duration::from_secs(60));
+        set_mtime_ago(&path, max_age * 24);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+    }
+
+    #[test]
+    fn touch_updates_checkouts_reused_without_a_fetch() {
+        let dir = TempDir::new("touch");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                touch: true,
+                ..Default::default()
+            },
+        );
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        set_mtime_ago(&path, day);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Linked);
+        let age = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap()
+            .elapsed()
+            .unwrap_or_default();
+        assert!(age < day / 2);
+    }
+
+    #[test]
+    fn checkouts_with_the_revision_are_only_fetched_when_forced() {
+        let dir = TempDir::new("skip-fetch");
+        let revisions = test_support::init_repo(&dir.join("checkout"), &["one"]);
+        let checkout = git2::Repository::open(dir.join("checkout")).unwrap();
+        let pin = pin("checkout", &dir.join("upstream"), revisions[0]);
+
+        let repo = package_repo(&dir, InstallOptions::default());
+        assert!(repo.reason_to_skip_fetch(&checkout, &pin).is_some());
+
+        let repo = package_repo(
+            &dir,
+            InstallOptions {
+                force_fetch: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(repo.reason_to_skip_fetch(&checkout, &pin), None);
+    }
+
+    #[test]
+    fn clones_and_fetches_check_out_the_pinned_revision() {
+        let dir = TempDir::new("pinned-checkout");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        assert_eq!(head(&path), Some(revisions[0].to_string()));
+
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        pin.state.revision = test_support::commit(&upstream, "two").to_string();
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Fetched);
+        assert_eq!(head(&path), Some(pin.state.revision.clone()));
+    }
+
+    #[test]
+    fn gc_removes_checkouts_no_resolved_file_uses() {
+        let dir = TempDir::new("gc");
+        let used_revisions = test_support::init_repo(&dir.join("used"), &["one"]);
+        let unused_revisions = test_support::init_repo(&dir.join("unused"), &["one"]);
+        let mut repo = package_repo(&dir, InstallOptions::default());
+        let used = pin("used", &dir.join("used"), used_revisions[0]);
+        let unused = pin("unused", &dir.join("unused"), unused_revisions[0]);
+        repo.clone(&used).unwrap();
+        repo.clone(&unused).unwrap();
+
+        let project = dir.join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        let resolved = v2::Resolved {
+            pins: vec![used.clone()],
+            version: 2,
+        };
+        std::fs::write(
+            project.join("Package.resolved"),
+            serde_json::to_string(&resolved).unwrap(),
+        )
+        .unwrap();
+
+        repo.gc(&project, true).unwrap();
+        assert!(repo.checkout_path(&unused).exists());
+
+        repo.gc(&project, false).unwrap();
+        assert!(repo.checkout_path(&used).exists());
+        assert!(!repo.checkout_path(&unused).exists());
+        assert!(!redirects(&repo, &repo.checkout_path(&used)).is_empty());
+        assert!(redirects(&repo, &repo.checkout_path(&unused)).is_empty());
+    }
+
+    #[test]
+    fn update_moves_checkouts_past_the_lockfile() {
+        let dir = TempDir::new("update-lock");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let mut repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+        let project = dir.join("project");
+        write_resolved(&project, &[pin.clone()]);
+        repo.install(&project, &InstallOptions::default()).unwrap();
+        assert!(project.join(lock::LOCK_FILE).exists());
+        assert_eq!(head(&path), Some(revisions[0].to_string()));
+
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        pin.state.revision = test_support::commit(&upstream, "two").to_string();
+        write_resolved(&project, &[pin.clone()]);
+        let update = InstallOptions {
+            existing_only: true,
+            ..Default::default()
+        };
+        repo.install(&project, &update).unwrap();
+        assert_eq!(head(&path), Some(pin.state.revision.clone()));
+
+        let locked = lock::read(&project).unwrap().unwrap();
+        assert_eq!(locked.pins[0].state.revision, pin.state.revision);
+    }
+
+    #[test]
+    fn status_compares_checkouts_to_the_locked_revision() {
+        let dir = TempDir::new("status-lock");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let mut repo = package_repo(&dir, InstallOptions::default());
+        let mut pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let project = dir.join("project");
+        write_resolved(&project, &[pin.clone()]);
+        repo.install(&project, &InstallOptions::default()).unwrap();
+        let lockfile = std::fs::read(project.join(lock::LOCK_FILE)).unwrap();
+
+        // The bump is not installed while the lockfile pins the old revision.
+        let upstream = git2::Repository::open(dir.join("upstream")).unwrap();
+        pin.state.revision = test_support::commit(&upstream, "two").to_string();
+        write_resolved(&project, &[pin.clone()]);
+        repo.status(&project, OutputFormat::Text).unwrap();
+        assert_eq!(
+            PackageRepo::installed_pins(&project).unwrap()[0].state.revision,
+            revisions[0].to_string()
+        );
+        assert_eq!(std::fs::read(project.join(lock::LOCK_FILE)).unwrap(), lockfile);
+
+        std::fs::remove_file(project.join(lock::LOCK_FILE)).unwrap();
+        assert!(matches!(
+            repo.status(&project, OutputFormat::Text),
+            Err(PackageRepoError::OutOfSync(1))
+        ));
+        assert!(!project.join(lock::LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn fail_fast_stops_starting_packages_after_a_failure() {
+        let dir = TempDir::new("fail-fast");
+        let revisions = test_support::init_repo(&dir.join("a"), &["one"]);
+        let working = test_support::init_repo(&dir.join("b"), &["one"]);
+        let project = dir.join("project");
+        let working = pin("b", &dir.join("b"), working[0]);
+        let failing = [
+            // Its remote doesn't exist.
+            (pin("a", &dir.join("missing"), revisions[0]), None),
+            // Its checkout hook fails.
+            (
+                pin("a", &dir.join("a"), revisions[0]),
+                Some(String::from("test \"$SPM_GIT_SWAP_IDENTITY\" != a")),
+            ),
+        ];
+
+        for (failing, checkout_hook) in failing {
+            for fail_fast in [false, true] {
+                let mut repo = package_repo(&dir, InstallOptions::default());
+                let _ = std::fs::remove_dir_all(repo.checkouts_dir());
+                write_resolved(&project, &[failing.clone(), working.clone()]);
+                let options = InstallOptions {
+                    checkout_hook: checkout_hook.clone(),
+                    fail_fast,
+                    update_lock: true,
+                    ..Default::default()
+                };
+
+                assert!(matches!(
+                    repo.install(&project, &options),
+                    Err(PackageRepoError::InstallFailed(1))
+                ));
+                assert_eq!(repo.checkout_path(&working).exists(), !fail_fast);
+            }
+        }
+    }
+
+    #[test]
+    fn unbundle_restores_checkouts_into_the_store() {
+        let dir = TempDir::new("unbundle-cas");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let mut repo =
+            package_repo(&dir, InstallOptions::default()).with_cas_dir(Some(dir.join("cas")));
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let path = repo.checkout_path(&pin);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+
+        assert!(matches!(
+            repo.bundle(&dir.join("cache.tar")),
+            Err(PackageRepoError::Bundle(_))
+        ));
+        assert!(!dir.join("cache.tar").exists());
+        let archive = dir.join("cache.zip");
+        repo.bundle(&archive).unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+        repo.remove_global_git_proxy(&path.display().to_string()).unwrap();
+        repo.unbundle(&archive).unwrap();
+        assert!(git2::Repository::open(&path).unwrap().is_bare());
+        assert_eq!(head(&path), Some(revisions[0].to_string()));
+        assert_eq!(redirects(&repo, &path), [pin.location]);
+        assert!(std::fs::read_dir(repo.checkouts_dir()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn relocate_moves_the_whole_repo_directory() {
+        let dir = TempDir::new("relocate");
+        let revisions = test_support::init_repo(&dir.join("upstream"), &["one"]);
+        let repo = package_repo(&dir, InstallOptions::default());
+        let pin = pin("upstream", &dir.join("upstream"), revisions[0]);
+        let old_path = repo.checkout_path(&pin);
+        assert_eq!(repo.clone(&pin).unwrap(), Action::Cloned);
+        std::fs::write(dir.join("repo").join(SETTINGS_FILE), "").unwrap();
+        std::fs::create_dir_all(dir.join("repo").join(BINARIES_DIR).join("binary")).unwrap();
+        let installed = manifest::INSTALLED_FILE;
+        repo.manifest()
+            .unwrap()
+            .write(&dir.join("repo").join(installed))
+            .unwrap();
+
+        PackageRepo::relocate(
+            &dir.join("repo"),
+            &dir.join("moved"),
+            Some(&dir.join("config")),
+        )
+        .unwrap();
+        assert!(!dir.join("repo").exists());
+        assert!(dir.join("moved").join(SETTINGS_FILE).exists());
+        assert!(dir.join("moved").join(BINARIES_DIR).join("binary").exists());
+
+        let moved = PackageRepo::new(&dir.join("moved"))
+            .unwrap()
+            .with_local_config(Some(dir.join("config")));
+        let new_path = moved.checkout_path(&pin);
+        assert_eq!(head(&new_path), Some(revisions[0].to_string()));
+        assert_eq!(redirects(&moved, &new_path), [pin.location.as_str()]);
+        assert!(redirects(&moved, &old_path).is_empty());
+        assert_eq!(
+            git_config::values(
+                &moved.proxy_config().unwrap(),
+                &git_config::managed_key(&new_path.display().to_string())
+            ),
+            [pin.location]
+        );
+        let manifest = Manifest::read(&dir.join("moved").join(installed)).unwrap();
+        assert_eq!(manifest.checkouts[0].path, new_path);
+    }
+
+    #[test]
+    fn relocate_refuses_a_destination_inside_the_repo_directory() {
+        let dir = TempDir::new("relocate-inside");
+        std::fs::create_dir_all(dir.join("repo").join(CHECKOUTS_DIR)).unwrap();
+
+        assert!(matches!(
+            PackageRepo::relocate(&dir.join("repo"), &dir.join("repo/moved"), None),
+            Err(PackageRepoError::Relocate(_))
+        ));
+        assert!(dir.join("repo").join(CHECKOUTS_DIR).exists());
+    }
 }
@@ -1,9 +1,17 @@
 use glob::glob;
-use log::info;
+use log::{debug, info, warn};
+use serde::Deserialize;
 
-use std::{collections::{HashMap, HashSet}, path::Path};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use thiserror::Error;
 
+use crate::url;
+
 #[derive(Debug, Error)]
 pub enum ResolvedError {
     #[error("IO error: {0}")]
@@ -18,58 +26,303 @@ pub enum ResolvedError {
     #[error("Pattern error: {0}")]
     Pattern(#[from] glob::PatternError),
 
-    #[error("Version not found in {0}. Version 1 and 2 are supported.")]
+    #[error("{0} does not exist")]
+    PathNotFound(Box<Path>),
+
+    #[error("{} is not valid UTF-8 and can't be scanned for resolved files", .0.display())]
+    NonUtf8Path(Box<Path>),
+
+    #[error("Version not found in {0}. Version 1, 2 and 3 are supported.")]
     VersionNotFound(Box<Path>),
+
+    #[error("{0} contains git conflict markers. Resolve the merge conflict and try again.")]
+    MergeConflict(Box<Path>),
+
+    #[error("Packages pinned differently across resolved files:\n{}", .0.join("\n"))]
+    ConflictingPins(Vec<String>),
 }
 
 pub fn parse_all_recursive(path: &Path) -> Result<Vec<v2::Pin>, ResolvedError> {
-    let mut pins: HashMap<String, v2::Pin> = HashMap::new();
-    for entry in glob(&format!("{}/**/Package.resolved", path.to_str().unwrap()))? {
+    merge_with(parse_files(path)?, DedupeStrategy::default())
+}
+
+/// Parses every resolved file under `path`, or `path` itself when it is a
+/// file, keeping track of which file each set of pins came from.
+pub fn parse_files(path: &Path) -> Result<Vec<(PathBuf, v2::Resolved)>, ResolvedError> {
+    parse_files_with(path, 1)
+}
+
+/// Like `parse_files`, but reads and parses the files on up to `jobs`
+/// threads. Files are found one after the other and the results, including
+/// which error is reported, keep that order regardless of `jobs`.
+pub fn parse_files_with(
+    path: &Path,
+    jobs: usize,
+) -> Result<Vec<(PathBuf, v2::Resolved)>, ResolvedError> {
+    if path.is_file() {
+        return Ok(vec![(path.to_path_buf(), parse(path)?)]);
+    }
+    if !path.exists() {
+        return Err(ResolvedError::PathNotFound(path.into()));
+    }
+
+    let mut paths = Vec::new();
+    let mut seen = HashSet::new();
+    let root = path
+        .to_str()
+        .ok_or_else(|| ResolvedError::NonUtf8Path(path.into()))?;
+    for entry in glob(&format!("{}/**/Package.resolved", root))? {
         let path = entry?;
-        for pin in parse(&path)?.pins {
-            pins.insert(pin.location.clone(), pin);
+        // Symlinked resolved files are found once per link, read each
+        // underlying file only once.
+        let canonical = path.canonicalize()?;
+        if !seen.insert(canonical.clone()) {
+            debug!("Skipping {:?}, already parsed as {:?}", path, canonical);
+            continue;
         }
+        paths.push(path);
     }
 
-    Ok(pins.into_values().collect())
+    let chunk_size = paths.len().div_ceil(jobs.max(1)).max(1);
+    let parsed = std::thread::scope(|scope| -> Result<Vec<_>, ResolvedError> {
+        let workers = paths
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                std::thread::Builder::new()
+                    .name(format!("scan-{}", index))
+                    .spawn_scoped(scope, move || {
+                        chunk.iter().map(|path| parse(path)).collect::<Vec<_>>()
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("resolved file parser panicked"))
+            .collect())
+    })?;
+
+    paths
+        .into_iter()
+        .zip(parsed)
+        .map(|(path, resolved)| Ok((path, resolved?)))
+        .collect()
 }
 
-pub fn parse(path: &Path) -> Result<v2::Resolved, ResolvedError> {
-    info!("Parsing resolved file: {:?}", path);
+/// Which pin is kept when several resolved files pin the same location.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupeStrategy {
+    /// The pin with a version over one without, and otherwise the pin from
+    /// the file scanned first.
+    #[default]
+    Versioned,
+    /// The pin from the file scanned last.
+    Last,
+    /// The pin from the file scanned first.
+    First,
+    /// The pin with the greatest semantic version. Pins without a version
+    /// lose to pins with one; ties keep the pin scanned last.
+    HighestVersion,
+    /// Refuse to pick, failing with every conflicting pin.
+    Error,
+}
 
-    let contents = std::fs::read_to_string(path)?;
-    let version = contents
-        .lines()
-        .rev() // Version seems to be at the bottom
-        .find_map(|line| {
-            if line.contains(r#""version""#) {
-                let stripped = line
-                    .chars()
-                    .filter(|c| c.is_alphanumeric() || *c == ':')
-                    .collect::<String>();
-                if stripped == "version:1" {
-                    return Some(1);
-                } else if stripped == "version:2" {
-                    return Some(2);
+impl FromStr for DedupeStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "versioned" => Ok(DedupeStrategy::Versioned),
+            "last" => Ok(DedupeStrategy::Last),
+            "first" => Ok(DedupeStrategy::First),
+            "highest-version" => Ok(DedupeStrategy::HighestVersion),
+            "error" => Ok(DedupeStrategy::Error),
+            _ => Err(format!(
+                "unknown dedupe strategy {}, expected versioned, last, first, highest-version or error",
+                value
+            )),
+        }
+    }
+}
+
+/// Combines the pins of several resolved files, keeping one pin per identity
+/// as chosen by `strategy`, sorted by identity. Pins of the same package can
+/// spell its location differently, such as with and without `.git`, but
+/// would still share a checkout.
+pub fn merge_with(
+    files: Vec<(PathBuf, v2::Resolved)>,
+    strategy: DedupeStrategy,
+) -> Result<Vec<v2::Pin>, ResolvedError> {
+    let mut pins: HashMap<String, (PathBuf, v2::Pin)> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for (file, resolved) in files {
+        for pin in resolved.pins {
+            let key = pin.identity.to_lowercase();
+            let replace = match pins.get(&key) {
+                None => true,
+                Some((existing_file, existing)) => match strategy {
+                    DedupeStrategy::Versioned => {
+                        existing.state.version.is_none() && pin.state.version.is_some()
+                    }
+                    DedupeStrategy::Last => true,
+                    DedupeStrategy::First => false,
+                    DedupeStrategy::HighestVersion => {
+                        compare_versions(&pin.state, &existing.state) != Ordering::Less
+                    }
+                    DedupeStrategy::Error => {
+                        if pinned_differently(existing, &pin) {
+                            conflicts.push(format!(
+                                "{} is pinned to {} at {} in {} and to {} at {} in {}",
+                                pin.identity,
+                                describe_state(&existing.state),
+                                existing.location,
+                                existing_file.display(),
+                                describe_state(&pin.state),
+                                pin.location,
+                                file.display()
+                            ));
+                        }
+                        false
+                    }
+                },
+            };
+            if let Some((existing_file, existing)) = pins.get(&key) {
+                if strategy != DedupeStrategy::Error && pinned_differently(existing, &pin) {
+                    let kept_file = if replace { &file } else { existing_file };
+                    warn!(
+                        "{} is pinned to {} at {} in {} and to {} at {} in {}, using the one from {}",
+                        pin.identity,
+                        describe_state(&existing.state),
+                        existing.location,
+                        existing_file.display(),
+                        describe_state(&pin.state),
+                        pin.location,
+                        file.display(),
+                        kept_file.display()
+                    );
                 }
             }
+            if replace {
+                pins.insert(key, (file.clone(), pin));
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(ResolvedError::ConflictingPins(conflicts));
+    }
+    let mut pins: Vec<v2::Pin> = pins.into_values().map(|(_, pin)| pin).collect();
+    pins.sort_by(|a, b| (&a.identity, &a.location).cmp(&(&b.identity, &b.location)));
+    Ok(pins)
+}
+
+/// Whether two pins of the same identity disagree on the revision or on the
+/// location, ignoring how the location is spelled.
+fn pinned_differently(a: &v2::Pin, b: &v2::Pin) -> bool {
+    a.state != b.state
+        || url::normalize_location(&a.location) != url::normalize_location(&b.location)
+}
+
+fn describe_state(state: &v2::State) -> String {
+    match &state.version {
+        Some(version) => format!("{} ({})", version, state.revision),
+        None => state.revision.clone(),
+    }
+}
 
-            return None;
-        });
+/// Orders two pin states by semantic version. Numeric components are
+/// compared numerically and a pre-release sorts before its release.
+fn compare_versions(a: &v2::State, b: &v2::State) -> Ordering {
+    fn key(version: &str) -> (Vec<u64>, bool, &str) {
+        let (release, pre) = version.split_once('-').unwrap_or((version, ""));
+        let numbers = release
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect();
+        (numbers, pre.is_empty(), pre)
+    }
+
+    match (&a.version, &b.version) {
+        (Some(a), Some(b)) => key(a).cmp(&key(b)),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Whether `pin` is fetched over plain, unencrypted http.
+pub fn is_insecure(pin: &v2::Pin) -> bool {
+    pin.location
+        .get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"))
+}
+
+pub fn parse(path: &Path) -> Result<v2::Resolved, ResolvedError> {
+    info!("Parsing resolved file: {:?}", path);
+
+    let contents = std::fs::read_to_string(path)?;
+    if has_conflict_markers(&contents) {
+        return Err(ResolvedError::MergeConflict(path.into()));
+    }
+    let version = probe_version(&contents).or_else(|| scan_version(&contents));
 
     match version {
         Some(1) => {
             info!("Parsing as version 1");
             Ok(v1::parse(path)?.into())
         }
-        Some(2) => {
-            info!("Parsing as version 2");
+        // Version 3 only adds the top-level `originHash`, which is ignored.
+        Some(version @ (2 | 3)) => {
+            info!("Parsing as version {}", version);
             Ok(v2::parse(path)?)
         }
         _ => Err(ResolvedError::VersionNotFound(path.into())),
     }
 }
 
+/// Whether a line starts with one of the markers git leaves in a file with
+/// an unresolved merge conflict.
+fn has_conflict_markers(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        ["<<<<<<<", "=======", ">>>>>>>", "|||||||"]
+            .iter()
+            .any(|marker| line.starts_with(marker))
+    })
+}
+
+/// Reads the top-level `version` field, wherever it appears in the file.
+fn probe_version(contents: &str) -> Option<u8> {
+    #[derive(Deserialize)]
+    struct Probe {
+        version: Option<u8>,
+    }
+
+    serde_json::from_str::<Probe>(contents).ok()?.version
+}
+
+/// Fallback for files that aren't valid JSON as a whole. The version is
+/// usually the last line, so search from the bottom.
+fn scan_version(contents: &str) -> Option<u8> {
+    contents.lines().rev().find_map(|line| {
+        if line.contains(r#""version""#) {
+            let stripped = line
+                .chars()
+                .filter(|c| c.is_alphanumeric() || *c == ':')
+                .collect::<String>();
+            if stripped == "version:1" {
+                return Some(1);
+            } else if stripped == "version:2" {
+                return Some(2);
+            } else if stripped == "version:3" {
+                return Some(3);
+            }
+        }
+
+        None
+    })
+}
+
 pub mod v2 {
     use super::ResolvedError;
     use serde::{Deserialize, Serialize};
@@ -99,8 +352,23 @@ pub mod v2 {
 
     #[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
     pub struct State {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub branch: Option<String>,
+        /// Empty for binary targets, which are pinned by checksum instead.
+        #[serde(default)]
         pub revision: String,
         pub version: Option<String>,
+        /// The SHA-256 of a binary target's zip archive.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub checksum: Option<String>,
+    }
+
+    impl State {
+        /// The branch to follow when a pin names one but no revision, so
+        /// the branch's tip is checked out instead.
+        pub fn followed_branch(&self) -> Option<&str> {
+            self.branch.as_deref().filter(|_| self.revision.is_empty())
+        }
     }
 
     pub(super) fn parse(path: &Path) -> Result<Resolved, ResolvedError> {
@@ -128,7 +396,8 @@ mod v1 {
     #[derive(Debug, Serialize, Deserialize)]
     pub(super) struct Pin {
         pub package: String,
-        pub repositoryURL: String,
+        #[serde(rename = "repositoryURL")]
+        pub repository_url: String,
         pub state: State,
     }
 
@@ -146,19 +415,21 @@ mod v1 {
     }
 }
 
-impl Into<v2::Resolved> for v1::Resolved {
-    fn into(self) -> v2::Resolved {
-        let pins = self
+impl From<v1::Resolved> for v2::Resolved {
+    fn from(resolved: v1::Resolved) -> Self {
+        let pins = resolved
             .object
             .pins
             .into_iter()
             .map(|pin| {
                 let identity = pin.package;
                 let kind = v2::Kind::RemoteSourceControl;
-                let location = pin.repositoryURL;
+                let location = pin.repository_url;
                 let state = v2::State {
+                    branch: pin.state.branch,
                     revision: pin.state.revision,
                     version: pin.state.version,
+                    checksum: None,
                 };
                 v2::Pin {
                     identity,
@@ -171,3 +442,218 @@ impl Into<v2::Resolved> for v1::Resolved {
         v2::Resolved { pins, version: 2 }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixture;
+
+    fn merged(strategy: DedupeStrategy) -> Result<Vec<v2::Pin>, ResolvedError> {
+        merge_with(parse_files(&fixture("conflicting")).unwrap(), strategy)
+    }
+
+    /// Each pin's identity and the start of its revision.
+    fn revisions(pins: &[v2::Pin]) -> Vec<(&str, &str)> {
+        pins.iter()
+            .map(|pin| (pin.identity.as_str(), &pin.state.revision[..4]))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_version_at_the_top_of_the_file() {
+        let resolved = parse(&fixture("version-first.resolved")).unwrap();
+        assert_eq!(resolved.pins.len(), 1);
+        assert_eq!(resolved.pins[0].identity, "swift-log");
+        assert_eq!(resolved.pins[0].state.version.as_deref(), Some("1.5.4"));
+    }
+
+    #[test]
+    fn reads_minified_files() {
+        let resolved = parse(&fixture("v1-minified.resolved")).unwrap();
+        let identities: Vec<&str> = resolved
+            .pins
+            .iter()
+            .map(|pin| pin.identity.as_str())
+            .collect();
+        assert_eq!(identities, ["Alamofire", "SnapKit"]);
+    }
+
+    #[test]
+    fn ignores_version_keys_inside_pins() {
+        // Scanning from the bottom takes the pin's `"version": "2"` for the
+        // file version.
+        let contents = std::fs::read_to_string(fixture("v1-nested-version.resolved")).unwrap();
+        assert_eq!(scan_version(&contents), Some(2));
+        assert_eq!(probe_version(&contents), Some(1));
+
+        let resolved = parse(&fixture("v1-nested-version.resolved")).unwrap();
+        assert_eq!(resolved.pins.len(), 1);
+        assert_eq!(resolved.pins[0].identity, "Nimble");
+        assert_eq!(resolved.pins[0].state.version.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn reads_version_3_files() {
+        let resolved = parse(&fixture("v3.resolved")).unwrap();
+        let identities: Vec<&str> = resolved
+            .pins
+            .iter()
+            .map(|pin| pin.identity.as_str())
+            .collect();
+        assert_eq!(identities, ["swift-argument-parser", "swift-collections"]);
+        assert_eq!(resolved.pins[0].state.version.as_deref(), Some("1.5.0"));
+        assert_eq!(resolved.pins[1].state.version, None);
+        assert_eq!(resolved.pins[1].state.branch.as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn keeps_the_branch_of_version_1_pins() {
+        let resolved = parse(&fixture("v1-branch.resolved")).unwrap();
+        assert_eq!(resolved.pins[0].state.branch.as_deref(), Some("main"));
+        assert_eq!(resolved.pins[1].state.branch, None);
+        assert_eq!(resolved.pins[1].state.version.as_deref(), Some("9.2.1"));
+    }
+
+    #[test]
+    fn scans_directories_for_resolved_files() {
+        let files = parse_files(&fixture("conflicting")).unwrap();
+        let paths: Vec<PathBuf> = files.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            [
+                fixture("conflicting/a/Package.resolved"),
+                fixture("conflicting/b/Package.resolved")
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_a_single_file_directly() {
+        // Whatever it is named.
+        let path = fixture("version-first.resolved");
+        let files = parse_files(&path).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, path);
+        assert_eq!(files[0].1.pins.len(), 1);
+    }
+
+    #[test]
+    fn missing_paths_are_an_error() {
+        let path = fixture("does-not-exist");
+        match parse_files(&path) {
+            Err(ResolvedError::PathNotFound(missing)) => assert_eq!(&*missing, path),
+            other => panic!("expected a missing path, got {:?}", other),
+        }
+    }
+
+    // Other platforms refuse to create such a directory.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn non_utf8_paths_are_an_error() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = crate::test_support::TempDir::new("non-utf8");
+        let path = dir.join(OsStr::from_bytes(b"caf\xe9"));
+        std::fs::create_dir(&path).unwrap();
+        match parse_files(&path) {
+            Err(ResolvedError::NonUtf8Path(rejected)) => assert_eq!(&*rejected, path),
+            other => panic!("expected a non UTF-8 path, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn files_with_conflict_markers_are_a_merge_conflict() {
+        let path = fixture("conflict-markers.resolved");
+        match parse(&path) {
+            Err(ResolvedError::MergeConflict(conflicted)) => assert_eq!(&*conflicted, path),
+            other => panic!("expected a merge conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pins_are_sorted_by_identity() {
+        let pins = parse_all_recursive(&fixture("unordered.resolved")).unwrap();
+        let identities: Vec<&str> = pins.iter().map(|pin| pin.identity.as_str()).collect();
+        assert_eq!(
+            identities,
+            [
+                "alamofire",
+                "grdb.swift",
+                "kingfisher",
+                "snapkit",
+                "swift-log",
+                "swift-nio"
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_pins_prefer_a_version_then_the_first_file() {
+        let pins = parse_all_recursive(&fixture("conflicting")).unwrap();
+        assert_eq!(
+            revisions(&pins),
+            [("bar", "bbbb"), ("baz", "1111"), ("qux", "3333")]
+        );
+    }
+
+    #[test]
+    fn last_keeps_the_pin_scanned_last() {
+        let pins = merged(DedupeStrategy::Last).unwrap();
+        assert_eq!(
+            revisions(&pins),
+            [("bar", "bbbb"), ("baz", "2222"), ("qux", "3333")]
+        );
+    }
+
+    #[test]
+    fn first_keeps_the_pin_scanned_first() {
+        let pins = merged(DedupeStrategy::First).unwrap();
+        assert_eq!(
+            revisions(&pins),
+            [("bar", "aaaa"), ("baz", "1111"), ("qux", "3333")]
+        );
+    }
+
+    #[test]
+    fn highest_version_compares_versions_numerically() {
+        // 1.10.0 beats 1.0.0, and any version beats none.
+        let pins = merged(DedupeStrategy::HighestVersion).unwrap();
+        assert_eq!(
+            revisions(&pins),
+            [("bar", "bbbb"), ("baz", "2222"), ("qux", "3333")]
+        );
+    }
+
+    #[test]
+    fn error_lists_every_conflict() {
+        match merged(DedupeStrategy::Error) {
+            Err(ResolvedError::ConflictingPins(mut conflicts)) => {
+                conflicts.sort();
+                assert_eq!(conflicts.len(), 2);
+                assert!(conflicts[0].starts_with("bar "));
+                assert!(conflicts[1].starts_with("baz "));
+            }
+            other => panic!("expected conflicting pins, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn semantic_versions_order_pre_releases_first() {
+        let state = |version: &str| v2::State {
+            branch: None,
+            revision: String::new(),
+            version: Some(version.to_string()),
+            checksum: None,
+        };
+        let ordered = ["1.0.0-beta.1", "1.0.0", "1.2.0", "1.10.0", "v2.0.0"];
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                compare_versions(&state(pair[0]), &state(pair[1])),
+                Ordering::Less,
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+}
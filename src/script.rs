@@ -0,0 +1,60 @@
+use std::{fmt::Write, path::Path};
+
+use crate::repo::PackageRepoError;
+
+/// A POSIX shell script of git commands, built up one line at a time.
+#[derive(Debug)]
+pub struct Script(String);
+
+impl Script {
+    pub fn new() -> Self {
+        Self(String::from(
+            "#!/bin/sh\n# Generated by spm-git-swap install --emit-script\nset -e\n",
+        ))
+    }
+
+    pub fn comment(&mut self, comment: &str) {
+        let _ = writeln!(self.0, "\n# {}", comment);
+    }
+
+    /// Appends a `git` invocation with each argument quoted for the shell.
+    pub fn git<S: AsRef<str>>(&mut self, args: &[S]) {
+        let _ = writeln!(self.0, "{}", command(args));
+    }
+
+    /// Like `git`, but carries on when the command fails, as `git config
+    /// --unset-all` does for keys that aren't set.
+    pub fn git_allow_failure<S: AsRef<str>>(&mut self, args: &[S]) {
+        let _ = writeln!(self.0, "{} || true", command(args));
+    }
+
+    /// Writes the script to `path` and marks it executable.
+    pub fn write(&self, path: &Path) -> Result<(), PackageRepoError> {
+        std::fs::write(path, &self.0)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+}
+
+fn command<S: AsRef<str>>(args: &[S]) -> String {
+    std::iter::once("git".to_string())
+        .chain(args.iter().map(|arg| quote(arg.as_ref())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:@=+,".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
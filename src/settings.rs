@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::repo::PackageRepoError;
+
+pub const SETTINGS_FILE: &str = "config.toml";
+
+/// Defaults for `install` read from `config.toml` in the repo directory.
+/// Flags given on the command line take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub timeout: Option<u64>,
+    pub git_protocol: Option<u8>,
+    pub url_map: Option<PathBuf>,
+    pub config_key_style: Option<String>,
+    pub reuse_spm_cache: Option<PathBuf>,
+    pub min_free_space: Option<u64>,
+    pub http_proxy: Option<String>,
+    pub remote_name: Option<String>,
+    pub cas_dir: Option<PathBuf>,
+    pub ssh_key: Option<PathBuf>,
+}
+
+const STARTER: &str = r#"# spm-git-swap configuration. Every setting is optional and can be
+# overridden by the matching command line flag.
+
+# Timeout in seconds for network operations.
+# timeout = 60
+
+# Git protocol version requested from http(s) remotes (0, 1 or 2).
+# git_protocol = 2
+
+# JSON or TOML file mapping package identities or locations to clone URLs.
+# url_map = "/path/to/mirrors.toml"
+
+# Which git config keys to write: "insteadOf", "pushInsteadOf" or "both".
+# config_key_style = "insteadOf"
+
+# Reuse repositories from SwiftPM's cache when they contain the pinned revision.
+# reuse_spm_cache = "/Users/me/Library/Caches/org.swift.swiftpm/repositories"
+
+# Abort when fewer than this many bytes are free.
+# min_free_space = 5000000000
+
+# Proxy for http(s) remotes, or "auto" to use git's http.proxy setting.
+# http_proxy = "http://proxy.example.com:8080"
+
+# Name of the remote created when cloning.
+# remote_name = "origin"
+
+# Keep bare clones in a content-addressed store shared with other tools.
+# cas_dir = "/var/cache/git-cas"
+
+# Private key used for ssh remotes instead of the ssh agent and ~/.ssh/id_*.
+# ssh_key = "/path/to/deploy_key"
+"#;
+
+impl Settings {
+    /// Reads the settings file in `repo_dir`. A missing file yields the
+    /// defaults.
+    pub fn load(repo_dir: &Path) -> Result<Self, PackageRepoError> {
+        let path = repo_dir.join(SETTINGS_FILE);
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+
+        let contents = std::fs::read_to_string(&path)?;
+        toml::from_str(&contents)
+            .map_err(|err| PackageRepoError::Settings(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Writes a commented starter settings file to `repo_dir` unless one
+    /// already exists. Returns whether a file was written.
+    pub fn write_starter(repo_dir: &Path) -> Result<bool, PackageRepoError> {
+        let path = repo_dir.join(SETTINGS_FILE);
+        if path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::write(path, STARTER)?;
+        Ok(true)
+    }
+}
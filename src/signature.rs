@@ -0,0 +1,135 @@
+use std::{
+    fmt,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::repo::PackageRepoError;
+
+/// Outcome of checking the signature on a pinned commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// Signed by the key with this fingerprint.
+    Good(String),
+    Unsigned,
+    Bad(String),
+    /// The pinned revision isn't in the checkout, even after fetching.
+    Missing,
+}
+
+impl SignatureStatus {
+    pub fn is_good(&self) -> bool {
+        matches!(self, SignatureStatus::Good(_))
+    }
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Good(fingerprint) => write!(f, "good signature from {}", fingerprint),
+            SignatureStatus::Unsigned => write!(f, "unsigned"),
+            SignatureStatus::Bad(reason) => write!(f, "bad signature: {}", reason),
+            SignatureStatus::Missing => write!(f, "revision not found"),
+        }
+    }
+}
+
+/// Checks the signature on `commit` with gpg, against `keyring` when given
+/// and the user's default keyring otherwise.
+pub fn verify_commit(
+    repo: &git2::Repository,
+    commit: git2::Oid,
+    keyring: Option<&Path>,
+) -> Result<SignatureStatus, PackageRepoError> {
+    let (signature, signed_data) = match repo.extract_signature(&commit, None) {
+        Ok(extracted) => extracted,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => {
+            return Ok(SignatureStatus::Unsigned)
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if !signature.starts_with(b"-----BEGIN PGP SIGNATURE-----") {
+        return Ok(SignatureStatus::Bad(String::from(
+            "unsupported signature format, only gpg signatures can be verified",
+        )));
+    }
+
+    // gpg reads the detached signature from a file and the signed data from
+    // stdin.
+    let signature_path =
+        std::env::temp_dir().join(format!("spm-git-swap-{}-{}.sig", std::process::id(), commit));
+    std::fs::write(&signature_path, &*signature)?;
+    let result = run_gpg(&signature_path, &signed_data, keyring);
+    let _ = std::fs::remove_file(&signature_path);
+    result
+}
+
+fn run_gpg(
+    signature_path: &Path,
+    signed_data: &[u8],
+    keyring: Option<&Path>,
+) -> Result<SignatureStatus, PackageRepoError> {
+    let mut command = Command::new("gpg");
+    command.args(["--batch", "--status-fd", "1"]);
+    if let Some(keyring) = keyring {
+        command
+            .arg("--no-default-keyring")
+            .arg("--keyring")
+            .arg(keyring.canonicalize()?);
+    }
+    command
+        .arg("--verify")
+        .arg(signature_path)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    let mut child = command.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(signed_data)?;
+    }
+    let output = child.wait_with_output()?;
+
+    Ok(parse_status(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Interprets gpg's machine-readable status lines.
+fn parse_status(status: &str) -> SignatureStatus {
+    let mut good = false;
+    let mut fingerprint = None;
+    let mut problem = None;
+
+    for line in status.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("[GNUPG:]") {
+            continue;
+        }
+        match fields.next() {
+            Some("GOODSIG") => good = true,
+            Some("VALIDSIG") => fingerprint = fields.next().map(String::from),
+            Some("BADSIG") => problem = Some(String::from("signature does not match")),
+            Some("EXPSIG") => problem = Some(String::from("signature has expired")),
+            Some("EXPKEYSIG") => problem = Some(String::from("signing key has expired")),
+            Some("REVKEYSIG") => problem = Some(String::from("signing key was revoked")),
+            Some("NO_PUBKEY") => {
+                problem = Some(format!(
+                    "key {} is not in the keyring",
+                    fields.next().unwrap_or("unknown")
+                ))
+            }
+            Some("ERRSIG") if problem.is_none() => {
+                problem = Some(String::from("signature could not be checked"))
+            }
+            _ => {}
+        }
+    }
+
+    match (problem, good, fingerprint) {
+        (Some(problem), _, _) => SignatureStatus::Bad(problem),
+        (None, true, Some(fingerprint)) => SignatureStatus::Good(fingerprint),
+        _ => SignatureStatus::Bad(String::from("gpg did not report a valid signature")),
+    }
+}
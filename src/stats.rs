@@ -0,0 +1,63 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+use crate::{resolved::v2, url};
+
+/// A summary of a set of resolved pins.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub total: usize,
+    pub by_host: BTreeMap<String, usize>,
+    pub by_kind: BTreeMap<String, usize>,
+    /// Pins with a semantic version.
+    pub versioned: usize,
+    /// Pins that only record a revision (branch or commit pins).
+    pub revision_only: usize,
+}
+
+impl Stats {
+    pub fn from_pins(pins: &[v2::Pin]) -> Self {
+        let mut stats = Stats {
+            total: pins.len(),
+            ..Default::default()
+        };
+
+        for pin in pins {
+            let host = url::host(&pin.location).unwrap_or_else(|| String::from("local"));
+            *stats.by_host.entry(host).or_default() += 1;
+            *stats.by_kind.entry(kind_name(&pin.kind)).or_default() += 1;
+            if pin.state.version.is_some() {
+                stats.versioned += 1;
+            } else {
+                stats.revision_only += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+fn kind_name(kind: &v2::Kind) -> String {
+    serde_json::to_value(kind)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_else(|| format!("{:?}", kind))
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Total pins: {}", self.total)?;
+        writeln!(f, "Versioned: {}", self.versioned)?;
+        writeln!(f, "Revision only: {}", self.revision_only)?;
+        writeln!(f, "By host:")?;
+        for (host, count) in &self.by_host {
+            writeln!(f, "  {}: {}", host, count)?;
+        }
+        writeln!(f, "By kind:")?;
+        for (kind, count) in &self.by_kind {
+            writeln!(f, "  {}: {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
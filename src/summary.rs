@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{output::Action, repo::PackageRepoError};
+
+/// What an install did, printed once it finishes so the outcome doesn't have
+/// to be pieced together from the log.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Summary {
+    cloned: usize,
+    fetched: usize,
+    linked: usize,
+    skipped: usize,
+    failed: usize,
+    failures: Vec<Failure>,
+    /// Existing checkouts that were moved to a different revision.
+    moved: Vec<Move>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Move {
+    identity: String,
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Failure {
+    identity: String,
+    error: Option<String>,
+}
+
+impl Summary {
+    pub fn record(&mut self, identity: &str, action: Action, error: Option<&str>) {
+        match action {
+            Action::Cloned => self.cloned += 1,
+            Action::Fetched => self.fetched += 1,
+            Action::Linked => self.linked += 1,
+            Action::Skipped => self.skipped += 1,
+            Action::Failed => {
+                self.failed += 1;
+                self.failures.push(Failure {
+                    identity: identity.to_string(),
+                    error: error.map(String::from),
+                });
+            }
+        }
+    }
+
+    pub fn moved(&mut self, identity: &str, from: &str, to: &str) {
+        self.moved.push(Move {
+            identity: identity.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+
+    fn total(&self) -> usize {
+        self.cloned + self.fetched + self.linked + self.skipped + self.failed
+    }
+
+    /// Logs the counts, every checkout that moved to a new revision and every
+    /// failed package with its error. Nothing is logged when no package was
+    /// processed, as in a dry run.
+    pub fn log(&mut self) {
+        if self.total() == 0 {
+            return;
+        }
+        info!(
+            "Summary: {} cloned, {} fetched, {} linked, {} skipped, {} failed",
+            self.cloned, self.fetched, self.linked, self.skipped, self.failed
+        );
+        self.moved
+            .sort_by(|left, right| left.identity.cmp(&right.identity));
+        for moved in &self.moved {
+            info!("  {} moved from {} to {}", moved.identity, moved.from, moved.to);
+        }
+        self.failures
+            .sort_by(|left, right| left.identity.cmp(&right.identity));
+        for failure in &self.failures {
+            error!(
+                "  {}: {}",
+                failure.identity,
+                failure.error.as_deref().unwrap_or("failed")
+            );
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), PackageRepoError> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+/// A token for every https remote, for CI runners without a credential
+/// helper.
+pub const TOKEN_VAR: &str = "SPM_GIT_TOKEN";
+
+/// The username sent with [`TOKEN_VAR`].
+pub const USERNAME_VAR: &str = "SPM_GIT_USERNAME";
+
+/// Only used for github.com, as many CI jobs set it for unrelated reasons.
+pub const GITHUB_TOKEN_VAR: &str = "GITHUB_TOKEN";
+
+/// GitHub accepts this username with every kind of token.
+const DEFAULT_USERNAME: &str = "x-access-token";
+
+/// Credentials for https remotes read from the environment.
+#[derive(Clone)]
+pub struct Token {
+    /// The variable the token was read from.
+    pub var: &'static str,
+    /// The host the token is sent to, or `*` for every host.
+    pub host: String,
+    pub username: String,
+    pub token: String,
+}
+
+// Written by hand so the token never ends up in a log.
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("var", &self.var)
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Token {
+    /// `SPM_GIT_TOKEN` for every host, or else `GITHUB_TOKEN` for github.com.
+    pub fn from_env() -> Option<Self> {
+        if let Some(token) = non_empty(TOKEN_VAR) {
+            return Some(Token {
+                var: TOKEN_VAR,
+                host: String::from("*"),
+                username: non_empty(USERNAME_VAR)
+                    .unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
+                token,
+            });
+        }
+        non_empty(GITHUB_TOKEN_VAR).map(|token| Token {
+            var: GITHUB_TOKEN_VAR,
+            host: String::from("github.com"),
+            username: DEFAULT_USERNAME.to_string(),
+            token,
+        })
+    }
+
+    pub fn applies_to(&self, host: &str) -> bool {
+        self.host == "*" || self.host.eq_ignore_ascii_case(host)
+    }
+}
+
+fn non_empty(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|value| !value.is_empty())
+}
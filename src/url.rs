@@ -0,0 +1,318 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{repo::PackageRepoError, resolved::v2};
+
+/// The host https locations are converted to ssh for by default, and the
+/// user to connect as.
+pub const DEFAULT_SSH_HOST: (&str, &str) = ("github.com", "git");
+
+/// Decides which URL each package is cloned from. The `insteadOf` config
+/// always redirects the pin's original location, so rewriting here only
+/// changes where the data is fetched from.
+#[derive(Debug, Clone)]
+pub struct UrlRewriter {
+    url_map: BTreeMap<String, String>,
+    replacements: BTreeMap<String, String>,
+    /// Hosts whose https locations are cloned over ssh, and the user to
+    /// connect as.
+    ssh_hosts: BTreeMap<String, String>,
+}
+
+impl Default for UrlRewriter {
+    fn default() -> Self {
+        let (host, user) = DEFAULT_SSH_HOST;
+        Self {
+            url_map: BTreeMap::new(),
+            replacements: BTreeMap::new(),
+            ssh_hosts: BTreeMap::from([(host.to_string(), user.to_string())]),
+        }
+    }
+}
+
+impl UrlRewriter {
+    /// Clones matching packages from alternate URLs. Keys are either package
+    /// identities or original locations.
+    pub fn with_url_map(mut self, url_map: BTreeMap<String, String>) -> Self {
+        self.url_map = url_map;
+        self
+    }
+
+    /// Clones packages located at an upstream URL from a fork instead. Takes
+    /// precedence over the url map.
+    pub fn with_replacements(mut self, replacements: Vec<(String, String)>) -> Self {
+        self.replacements = replacements.into_iter().collect();
+        self
+    }
+
+    /// Also clones https locations on these hosts over ssh, connecting as the
+    /// paired user. Overrides the user of a host that is already converted.
+    pub fn with_ssh_hosts(mut self, ssh_hosts: Vec<(String, String)>) -> Self {
+        self.ssh_hosts.extend(
+            ssh_hosts
+                .into_iter()
+                .map(|(host, user)| (host.to_lowercase(), user)),
+        );
+        self
+    }
+
+    /// Clones every location as written, without converting any to ssh.
+    pub fn without_ssh(mut self) -> Self {
+        self.ssh_hosts.clear();
+        self
+    }
+
+    /// Reads a url map from a JSON or TOML (`.toml` extension) file containing
+    /// a flat table of identity or location to clone URL.
+    pub fn load_url_map(path: &Path) -> Result<BTreeMap<String, String>, PackageRepoError> {
+        let contents = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            toml::from_str(&contents).map_err(|err| {
+                PackageRepoError::UrlMap(format!("{}: {}", path.display(), err))
+            })
+        } else {
+            serde_json::from_str(&contents).map_err(|err| {
+                PackageRepoError::UrlMap(format!("{}: {}", path.display(), err))
+            })
+        }
+    }
+
+    /// Returns the URL `pin` should be cloned from.
+    pub fn rewrite(&self, pin: &v2::Pin) -> String {
+        self.mapped(pin)
+            .unwrap_or_else(|| self.convert_to_ssh(&pin.location))
+    }
+
+    /// Returns the URL a binary target's archive should be downloaded from.
+    /// Only the replacements and the url map apply, as archives are always
+    /// downloaded over http(s).
+    pub fn rewrite_download(&self, pin: &v2::Pin) -> String {
+        self.mapped(pin).unwrap_or_else(|| pin.location.clone())
+    }
+
+    fn mapped(&self, pin: &v2::Pin) -> Option<String> {
+        self.replacements
+            .get(&pin.location)
+            .or_else(|| self.url_map.get(&pin.identity))
+            .or_else(|| self.url_map.get(&pin.location))
+            .cloned()
+    }
+
+    /// Converts an https location on one of the ssh hosts to the scp-like
+    /// `user@host:path` form, so the default ssh keys can be used for
+    /// authentication. The path is normalized, so a trailing `/` or `.git`
+    /// doesn't change the result. Any other location is returned unchanged.
+    pub fn convert_to_ssh(&self, location: &str) -> String {
+        let Some(rest) = location
+            .split_once("://")
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("https"))
+            .map(|(_, rest)| rest)
+        else {
+            return location.to_string();
+        };
+        let Some((authority, path)) = rest.split_once('/') else {
+            return location.to_string();
+        };
+        let path = normalize_location(path);
+        // Credentials and the https port don't carry over to ssh.
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        let host = host.split(':').next().unwrap_or(host).to_lowercase();
+
+        match self.ssh_hosts.get(&host) {
+            Some(user) if !path.is_empty() => format!("{}@{}:{}", user, host, path),
+            _ => location.to_string(),
+        }
+    }
+}
+
+/// Returns the transport a URL uses: `https`, `http`, `ssh`, `file` or
+/// whatever other scheme it names.
+pub fn protocol(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, _)) => scheme.to_lowercase(),
+        // scp-like `user@host:path`, but not a Windows drive letter.
+        None if url.split_once(':').is_some_and(|(host, _)| host.len() > 1) => {
+            String::from("ssh")
+        }
+        None => String::from("file"),
+    }
+}
+
+/// Extracts the host from an https, ssh or scp-like (`user@host:path`) URL.
+pub fn host(url: &str) -> Option<String> {
+    let rest = match url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => url.split_once(':')?.0,
+    };
+
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// Hides the password, or a lone token, in the userinfo of an http(s) URL,
+/// so the URL can be logged.
+pub fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let Some((userinfo, _)) = authority.rsplit_once('@') else {
+        return url.to_string();
+    };
+    if !scheme.eq_ignore_ascii_case("https") && !scheme.eq_ignore_ascii_case("http") {
+        return url.to_string();
+    }
+    let redacted = match userinfo.split_once(':') {
+        Some((user, _)) => format!("{}:***", user),
+        None => String::from("***"),
+    };
+    format!("{}://{}{}", scheme, redacted, &rest[userinfo.len()..])
+}
+
+/// The form locations are compared in: without trailing `/`s or a `.git`
+/// suffix, so `https://host/repo`, `https://host/repo.git` and
+/// `https://host/repo/` name the same package.
+pub fn normalize_location(location: &str) -> String {
+    let location = location.trim_end_matches('/');
+    location.strip_suffix(".git").unwrap_or(location).to_string()
+}
+
+/// The package identity SwiftPM derives from a URL: its last path component,
+/// lowercased, without a `.git` suffix.
+pub fn identity(url: &str) -> String {
+    let url = normalize_location(url);
+    let name = url.rsplit(['/', ':']).next().unwrap_or(&url);
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn pin(identity: &str, location: &str) -> v2::Pin {
+        v2::Pin {
+            identity: identity.to_string(),
+            kind: v2::Kind::RemoteSourceControl,
+            location: location.to_string(),
+            state: v2::State {
+                branch: None,
+                revision: String::from("e97a6fcb1ab07462881ac165fdbb37f067e205d5"),
+                version: None,
+                checksum: None,
+            },
+        }
+    }
+
+    const SPELLINGS: [&str; 3] = [
+        "https://github.com/foo/bar",
+        "https://github.com/foo/bar.git",
+        "https://github.com/foo/bar/",
+    ];
+
+    #[test]
+    fn locations_normalize_to_one_canonical_form() {
+        for location in SPELLINGS {
+            assert_eq!(normalize_location(location), "https://github.com/foo/bar");
+        }
+        assert_eq!(
+            normalize_location("https://github.com/foo/bar.git/"),
+            "https://github.com/foo/bar"
+        );
+    }
+
+    #[test]
+    fn every_spelling_converts_to_the_same_ssh_url() {
+        let rewriter = UrlRewriter::default();
+        for location in SPELLINGS {
+            assert_eq!(rewriter.convert_to_ssh(location), "git@github.com:foo/bar");
+            assert_eq!(identity(location), "bar");
+        }
+    }
+
+    #[test]
+    fn url_map_matches_identities_and_locations() {
+        let rewriter = UrlRewriter::default().with_url_map(BTreeMap::from([
+            (
+                String::from("swift-log"),
+                String::from("https://mirror.example.com/swift-log.git"),
+            ),
+            (
+                String::from("https://github.com/apple/swift-nio.git"),
+                String::from("https://mirror.example.com/swift-nio.git"),
+            ),
+        ]));
+
+        let log = pin("swift-log", "https://github.com/apple/swift-log.git");
+        assert_eq!(
+            rewriter.rewrite(&log),
+            "https://mirror.example.com/swift-log.git"
+        );
+        let nio = pin("swift-nio", "https://github.com/apple/swift-nio.git");
+        assert_eq!(
+            rewriter.rewrite(&nio),
+            "https://mirror.example.com/swift-nio.git"
+        );
+    }
+
+    #[test]
+    fn url_map_leaves_unmatched_pins_alone() {
+        let rewriter = UrlRewriter::default().with_url_map(BTreeMap::from([(
+            String::from("swift-log"),
+            String::from("https://mirror.example.com/swift-log.git"),
+        )]));
+
+        let pin = pin("swift-nio", "https://gitlab.com/apple/swift-nio.git");
+        assert_eq!(rewriter.rewrite(&pin), pin.location);
+    }
+
+    #[test]
+    fn replacements_win_over_the_url_map() {
+        let location = "https://github.com/apple/swift-log.git";
+        let rewriter = UrlRewriter::default()
+            .with_url_map(BTreeMap::from([(
+                String::from("swift-log"),
+                String::from("https://mirror.example.com/swift-log.git"),
+            )]))
+            .with_replacements(vec![(
+                location.to_string(),
+                String::from("https://github.com/fork/swift-log.git"),
+            )]);
+
+        let pin = pin("swift-log", location);
+        assert_eq!(
+            rewriter.rewrite(&pin),
+            "https://github.com/fork/swift-log.git"
+        );
+    }
+
+    #[test]
+    fn loads_url_maps_from_json_and_toml() {
+        let dir = TempDir::new("url-map");
+        let json = dir.join("mirrors.json");
+        std::fs::write(
+            &json,
+            r#"{"swift-log": "https://mirror.example.com/swift-log.git"}"#,
+        )
+        .unwrap();
+        let toml = dir.join("mirrors.toml");
+        std::fs::write(
+            &toml,
+            r#""swift-log" = "https://mirror.example.com/swift-log.git""#,
+        )
+        .unwrap();
+
+        let expected = BTreeMap::from([(
+            String::from("swift-log"),
+            String::from("https://mirror.example.com/swift-log.git"),
+        )]);
+        assert_eq!(UrlRewriter::load_url_map(&json).unwrap(), expected);
+        assert_eq!(UrlRewriter::load_url_map(&toml).unwrap(), expected);
+    }
+}